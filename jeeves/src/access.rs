@@ -0,0 +1,93 @@
+use crate::tools::find_tool_by_command;
+use crate::types::*;
+
+/// Discord permission bits that let a member run the guild, and so run Jeeves.
+const ADMINISTRATOR: u64 = 1 << 3;
+const MANAGE_GUILD: u64 = 1 << 5;
+
+/// Commands anyone Jeeves listens to may use; all others change how he behaves
+/// and are for those who manage the guild.
+const OPEN_COMMANDS: [&str; 2] = ["help", "status"];
+
+/// Whether a member's permissions, as Discord sends them with an interaction,
+/// let them configure Jeeves. Owners hold every permission.
+pub fn can_configure(permissions: Option<&str>) -> bool {
+    permissions
+        .and_then(|p| p.parse::<u64>().ok())
+        .map(|p| p & (ADMINISTRATOR | MANAGE_GUILD) != 0)
+        .unwrap_or(false)
+}
+
+pub fn is_open_command(command: &str) -> bool {
+    OPEN_COMMANDS.contains(&command) || find_tool_by_command(command).is_some()
+}
+
+/// Whether Jeeves should listen to this author, by their user ID and member role IDs.
+/// Denials always win; an empty allow list lets everyone through.
+pub fn is_listened_to(guild: &GuildInfo, user_id: &str, role_ids: &[String]) -> bool {
+    if guild.ignore_users.iter().any(|u| u == user_id) {
+        return false;
+    }
+    if role_ids.iter().any(|r| guild.ignore_roles.contains(r)) {
+        return false;
+    }
+    if guild.listen_to_users.is_empty() && guild.listen_to_roles.is_empty() {
+        return true;
+    }
+    guild.listen_to_users.iter().any(|u| u == user_id)
+        || role_ids.iter().any(|r| guild.listen_to_roles.contains(r))
+}
+
+pub fn access_list_mut<'a>(guild: &'a mut GuildInfo, list: &str) -> Option<&'a mut Vec<String>> {
+    match list {
+        "listen_to_roles" => Some(&mut guild.listen_to_roles),
+        "ignore_roles" => Some(&mut guild.ignore_roles),
+        "listen_to_users" => Some(&mut guild.listen_to_users),
+        "ignore_users" => Some(&mut guild.ignore_users),
+        _ => None,
+    }
+}
+
+pub fn describe_access_lists(guild: &GuildInfo) -> String {
+    let fmt = |ids: &Vec<String>, prefix: &str| {
+        if ids.is_empty() {
+            "(none)".to_string()
+        } else {
+            ids.iter()
+                .map(|id| format!("<{}{}>", prefix, id))
+                .collect::<Vec<String>>()
+                .join(", ")
+        }
+    };
+    format!(
+        "Listening to roles: {}\nIgnoring roles: {}\nListening to users: {}\nIgnoring users: {}",
+        fmt(&guild.listen_to_roles, "@&"),
+        fmt(&guild.ignore_roles, "@&"),
+        fmt(&guild.listen_to_users, "@"),
+        fmt(&guild.ignore_users, "@"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_administrators_and_managers_configure() {
+        assert!(can_configure(Some("8")));
+        assert!(can_configure(Some("32")));
+        assert!(can_configure(Some("2147483647")));
+        assert!(!can_configure(Some("2048")));
+        assert!(!can_configure(Some("not a number")));
+        assert!(!can_configure(None));
+    }
+
+    #[test]
+    fn help_status_and_tools_are_open() {
+        assert!(is_open_command("help"));
+        assert!(is_open_command("status"));
+        assert!(is_open_command("calc"));
+        assert!(!is_open_command("access"));
+        assert!(!is_open_command("quota"));
+    }
+}
@@ -0,0 +1,450 @@
+/// A value is kept as an exact fraction for as long as it can be, so that
+/// `0.1 + 0.2` is `3/10`, and only becomes a float once it has to: roots,
+/// trigonometry, fractional powers, or numbers too large for a fraction.
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    /// numerator and denominator, in lowest terms, the denominator positive
+    Exact(i128, i128),
+    Approx(f64),
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn fraction(n: i128, d: i128) -> Value {
+    // i128::MIN has no positive counterpart to normalize the sign with
+    if n == i128::MIN || d == i128::MIN {
+        return Value::Approx(n as f64 / d as f64);
+    }
+    let sign = if d < 0 { -1 } else { 1 };
+    let g = gcd(n, d).max(1);
+    Value::Exact(sign * n / g, sign * d / g)
+}
+
+impl Value {
+    fn to_f64(self) -> f64 {
+        match self {
+            Value::Exact(n, d) => n as f64 / d as f64,
+            Value::Approx(x) => x,
+        }
+    }
+
+    fn is_zero(self) -> bool {
+        match self {
+            Value::Exact(n, _) => n == 0,
+            Value::Approx(x) => x == 0.0,
+        }
+    }
+
+    fn add(self, other: Value) -> Value {
+        if let (Value::Exact(a, b), Value::Exact(c, d)) = (self, other) {
+            let n = a
+                .checked_mul(d)
+                .zip(c.checked_mul(b))
+                .and_then(|(x, y)| x.checked_add(y));
+            if let (Some(n), Some(d)) = (n, b.checked_mul(d)) {
+                return fraction(n, d);
+            }
+        }
+        Value::Approx(self.to_f64() + other.to_f64())
+    }
+
+    fn neg(self) -> Value {
+        match self {
+            Value::Exact(n, d) => match n.checked_neg() {
+                Some(n) => Value::Exact(n, d),
+                None => Value::Approx(-(n as f64 / d as f64)),
+            },
+            Value::Approx(x) => Value::Approx(-x),
+        }
+    }
+
+    fn mul(self, other: Value) -> Value {
+        if let (Value::Exact(a, b), Value::Exact(c, d)) = (self, other) {
+            if let (Some(n), Some(d)) = (a.checked_mul(c), b.checked_mul(d)) {
+                return fraction(n, d);
+            }
+        }
+        Value::Approx(self.to_f64() * other.to_f64())
+    }
+
+    fn recip(self) -> Result<Value, String> {
+        if self.is_zero() {
+            return Err("division by zero".to_string());
+        }
+        Ok(match self {
+            Value::Exact(n, d) => fraction(d, n),
+            Value::Approx(x) => Value::Approx(1.0 / x),
+        })
+    }
+
+    fn rem(self, other: Value) -> Result<Value, String> {
+        if other.is_zero() {
+            return Err("division by zero".to_string());
+        }
+        if let (Value::Exact(a, b), Value::Exact(c, d)) = (self, other) {
+            let r = a
+                .checked_mul(d)
+                .zip(c.checked_mul(b))
+                .and_then(|(x, y)| x.checked_rem(y));
+            if let (Some(r), Some(z)) = (r, b.checked_mul(d)) {
+                return Ok(fraction(r, z));
+            }
+        }
+        Ok(Value::Approx(self.to_f64() % other.to_f64()))
+    }
+
+    fn pow(self, exponent: Value) -> Result<Value, String> {
+        if let Value::Exact(e, 1) = exponent {
+            if e.unsigned_abs() <= 1000 {
+                let mut result = Value::Exact(1, 1);
+                for _ in 0..e.unsigned_abs() {
+                    result = result.mul(self);
+                }
+                return if e < 0 { result.recip() } else { Ok(result) };
+            }
+        }
+        Ok(Value::Approx(self.to_f64().powf(exponent.to_f64())))
+    }
+
+    fn round_with(self, f: fn(f64) -> f64) -> Value {
+        match self {
+            Value::Exact(n, d) => match (n as f64 / d as f64, d) {
+                (_, 1) => self,
+                (x, _) => Value::Exact(f(x) as i128, 1),
+            },
+            Value::Approx(x) => Value::Approx(f(x)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Value),
+    Ident(String),
+    Op(char),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.to_f64() == other.to_f64()
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars = expression.chars().collect::<Vec<char>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_digit() || chars[i] == '.' || chars[i] == '_')
+            {
+                i += 1;
+            }
+            let text = chars[start..i]
+                .iter()
+                .filter(|c| **c != '_')
+                .collect::<String>();
+            tokens.push(Token::Number(parse_decimal(&text)?));
+        } else if c.is_alphabetic() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(
+                chars[start..i].iter().collect::<String>().to_lowercase(),
+            ));
+        } else if "+-*/%^(),".contains(c) {
+            tokens.push(Token::Op(c));
+            i += 1;
+        } else if c == '×' {
+            tokens.push(Token::Op('*'));
+            i += 1;
+        } else if c == '÷' {
+            tokens.push(Token::Op('/'));
+            i += 1;
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+/// "1.25" is exactly 125/100.
+fn parse_decimal(text: &str) -> Result<Value, String> {
+    let (whole, frac) = text.split_once('.').unwrap_or((text, ""));
+    if frac.contains('.') || (whole.is_empty() && frac.is_empty()) {
+        return Err(format!("{} is not a number", text));
+    }
+    let digits = format!("{}{}", whole, frac);
+    let exact = digits
+        .parse::<i128>()
+        .ok()
+        .zip(10i128.checked_pow(frac.len() as u32));
+    match exact {
+        Some((n, d)) => Ok(fraction(n, d)),
+        None => text
+            .parse::<f64>()
+            .map(Value::Approx)
+            .map_err(|_| format!("{} is not a number", text)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: char) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: char) -> Result<(), String> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", op))
+        }
+    }
+
+    fn expr(&mut self) -> Result<Value, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value = value.add(self.term()?);
+            } else if self.eat('-') {
+                value = value.add(self.term()?.neg());
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Value, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value = value.mul(self.unary()?);
+            } else if self.eat('/') {
+                value = value.mul(self.unary()?.recip()?);
+            } else if self.eat('%') {
+                value = value.rem(self.unary()?)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Value, String> {
+        if self.eat('-') {
+            return Ok(self.unary()?.neg());
+        }
+        if self.eat('+') {
+            return self.unary();
+        }
+        let base = self.primary()?;
+        if self.eat('^') {
+            // right-associative, and binds tighter than a leading minus: -2^2 is -4
+            return base.pow(self.unary()?);
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Value, String> {
+        match self.peek().cloned() {
+            Some(Token::Number(value)) => {
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(Token::Op('(')) => {
+                self.pos += 1;
+                let value = self.expr()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                if !self.eat('(') {
+                    return constant(&name);
+                }
+                let mut args = vec![self.expr()?];
+                while self.eat(',') {
+                    args.push(self.expr()?);
+                }
+                self.expect(')')?;
+                call(&name, &args)
+            }
+            Some(Token::Op(op)) => Err(format!("unexpected '{}'", op)),
+            None => Err("the expression ends too soon".to_string()),
+        }
+    }
+}
+
+fn constant(name: &str) -> Result<Value, String> {
+    match name {
+        "pi" | "π" => Ok(Value::Approx(std::f64::consts::PI)),
+        "tau" => Ok(Value::Approx(std::f64::consts::TAU)),
+        "e" => Ok(Value::Approx(std::f64::consts::E)),
+        _ => Err(format!("unknown constant {}", name)),
+    }
+}
+
+fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+    let one = |f: fn(f64) -> f64| match args {
+        [x] => Ok(Value::Approx(f(x.to_f64()))),
+        _ => Err(format!("{} takes one argument", name)),
+    };
+    match name {
+        "sqrt" => match args {
+            [Value::Exact(n, d)] if *n >= 0 => {
+                let (rn, rd) = ((*n as f64).sqrt() as i128, (*d as f64).sqrt() as i128);
+                if rn.checked_mul(rn) == Some(*n) && rd.checked_mul(rd) == Some(*d) {
+                    Ok(Value::Exact(rn, rd))
+                } else {
+                    one(f64::sqrt)
+                }
+            }
+            _ => one(f64::sqrt),
+        },
+        "abs" => match args {
+            [Value::Exact(n, d)] if *n != i128::MIN => Ok(Value::Exact(n.abs(), *d)),
+            _ => one(f64::abs),
+        },
+        "floor" | "ceil" | "round" => match args {
+            [x] => Ok(x.round_with(match name {
+                "floor" => f64::floor,
+                "ceil" => f64::ceil,
+                _ => f64::round,
+            })),
+            _ => Err(format!("{} takes one argument", name)),
+        },
+        "sin" => one(f64::sin),
+        "cos" => one(f64::cos),
+        "tan" => one(f64::tan),
+        "asin" => one(f64::asin),
+        "acos" => one(f64::acos),
+        "atan" => one(f64::atan),
+        "exp" => one(f64::exp),
+        "ln" => one(f64::ln),
+        "log" => match args {
+            [x] => Ok(Value::Approx(x.to_f64().log10())),
+            [x, base] => Ok(Value::Approx(x.to_f64().log(base.to_f64()))),
+            _ => Err("log takes a number and optionally a base".to_string()),
+        },
+        "min" | "max" if !args.is_empty() => {
+            let mut best = args[0];
+            for x in &args[1..] {
+                let better = if name == "min" {
+                    x.to_f64() < best.to_f64()
+                } else {
+                    x.to_f64() > best.to_f64()
+                };
+                if better {
+                    best = *x;
+                }
+            }
+            Ok(best)
+        }
+        _ => Err(format!("unknown function {}", name)),
+    }
+}
+
+/// Evaluate an arithmetic expression: `+ - * / % ^`, parentheses, the constants
+/// `pi`, `tau` and `e`, and the functions sqrt, abs, floor, ceil, round, sin, cos,
+/// tan, asin, acos, atan, exp, ln, log, min and max.
+pub fn evaluate(expression: &str) -> anyhow::Result<String> {
+    if expression.len() > 500 {
+        return Err(anyhow::anyhow!("that expression is too long"));
+    }
+    let mut parser = Parser {
+        tokens: tokenize(expression).map_err(anyhow::Error::msg)?,
+        pos: 0,
+    };
+    let value = parser.expr().map_err(anyhow::Error::msg)?;
+    if let Some(token) = parser.peek() {
+        return Err(anyhow::anyhow!(
+            "unexpected {:?} after the expression",
+            token
+        ));
+    }
+    match value {
+        Value::Exact(n, 1) => Ok(n.to_string()),
+        Value::Exact(n, d) => Ok(format!("{}/{} (≈ {})", n, d, n as f64 / d as f64)),
+        // past what a float holds exactly, don't print digits that look exact
+        Value::Approx(x) if x.is_finite() && x != 0.0 && (x.abs() >= 1e15 || x.abs() < 1e-9) => {
+            Ok(format!("{:e}", x))
+        }
+        Value::Approx(x) if x.is_finite() => Ok(x.to_string()),
+        Value::Approx(_) => Err(anyhow::anyhow!("the result is not a finite number")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> String {
+        evaluate(expression).unwrap()
+    }
+
+    #[test]
+    fn keeps_fractions_exact() {
+        assert_eq!(eval("0.1 + 0.2"), "3/10 (≈ 0.3)");
+        assert_eq!(eval("1/3 * 3"), "1");
+        assert_eq!(eval("2^-2"), "1/4 (≈ 0.25)");
+        assert_eq!(eval("7.5 % 2"), "3/2 (≈ 1.5)");
+    }
+
+    #[test]
+    fn follows_precedence() {
+        assert_eq!(eval("2 + 3 * 4"), "14");
+        assert_eq!(eval("(2 + 3) * 4"), "20");
+        assert_eq!(eval("-2^2"), "-4");
+        assert_eq!(eval("2^3^2"), "512");
+        assert_eq!(eval("10 - 4 - 3"), "3");
+    }
+
+    #[test]
+    fn survives_overflow() {
+        // i128::MIN % -1 and -i128::MIN overflow an i128
+        let min = "(-170141183460469231731687303715884105727 - 1)";
+        assert!(evaluate(&format!("{} % -1", min)).is_ok());
+        assert!(evaluate(&format!("-({})", min)).is_ok());
+        assert!(evaluate(&format!("abs({})", min)).is_ok());
+        assert!(evaluate("99999999999999999999 ^ 3").is_ok());
+        // the float root of i128::MAX rounds up past the largest exact root
+        assert!(evaluate("sqrt(170141183460469231731687303715884105727)").is_ok());
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("5 % 0").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("2 +").is_err());
+        assert!(evaluate("1 2").is_err());
+        assert!(evaluate("foo(1)").is_err());
+        assert!(evaluate("2 $ 3").is_err());
+        assert!(evaluate(&"1+".repeat(300)).is_err());
+    }
+}
@@ -0,0 +1,27 @@
+use chrono::Utc;
+use chrono_tz::Tz;
+
+/// The current time in an IANA time zone such as `Europe/London`, or UTC.
+pub fn current_time(time_zone: Option<&str>) -> anyhow::Result<String> {
+    let now = Utc::now();
+    let Some(name) = time_zone.map(|t| t.trim()).filter(|t| !t.is_empty()) else {
+        return Ok(now.format("%A %-d %B %Y, %H:%M:%S UTC").to_string());
+    };
+    // let people write `new_york` or `America/New York`
+    let wanted = name.replace(' ', "_").to_lowercase();
+    let Some(tz) = chrono_tz::TZ_VARIANTS.iter().find(|tz| {
+        let tz_name = tz.name().to_lowercase();
+        tz_name == wanted || tz_name.rsplit('/').next() == Some(wanted.as_str())
+    }) else {
+        return Err(anyhow::anyhow!(
+            "{} is not a time zone I know; try a name like America/New_York",
+            name
+        ));
+    };
+    let local = now.with_timezone::<Tz>(tz);
+    Ok(format!(
+        "{} ({})",
+        local.format("%A %-d %B %Y, %H:%M:%S %Z (UTC%:z)"),
+        tz.name()
+    ))
+}
@@ -1,8 +1,22 @@
 use std::collections::HashMap;
 
+use crate::access::*;
+use crate::consts::*;
 use crate::discord::*;
 use crate::empty_state;
+use crate::genparams::*;
+use crate::knowledge::*;
+use crate::memory::*;
+use crate::models::*;
+use crate::quotas::*;
+use crate::ratelimit::*;
+use crate::speakers::*;
+use crate::templates::*;
+use crate::tools::*;
+use crate::triggers::*;
 use crate::types::*;
+use crate::usage::*;
+use discord_api::Attachment;
 use discord_api::BotId;
 use discord_api::InteractionData;
 use kinode_process_lib::{
@@ -18,14 +32,30 @@ pub fn respond_with_help(
     interaction_token: String,
 ) -> anyhow::Result<()> {
     let content: String = r#"Greetings, sir. I am Jeeves, your most humble assistant.
-In order to utilize my features, you may avail your esteemed self of one of the following commands.
+In order to utilize my features, you may avail your esteemed self of one of the following commands. All but `/help`, `/status`, `/calc`, `/roll`, `/time` and `/convert` are reserved for those who manage the guild.
 
 `/help`: Show this help message
 `/clear`: Make Jeeves forget the conversation thus far
 `/init`: Tell Jeeves to start responding to messages in this channel
 `/leave`: Tell Jeeves to stop responding to messages in this channel
-`/model`: Change the language model Jeeves is using (`gpt-4`, `gpt-3.5-turbo`)
+`/model`: Change the language model Jeeves is using, in this channel or the whole guild, or list those he knows
+`/prompt`: Show or change the instructions Jeeves follows, in this channel or the whole guild
+`/models`: List the models Jeeves may use, with their providers, context lengths, talents and prices; the node's owner adds and removes them from the Jeeves page
 `/status`: See what channels Jeeves is in, the size of message logs, model data, etc.
+`/access`: Show or edit the roles and users Jeeves listens to or ignores
+`/capture`: Choose whether Jeeves keeps every message (off unless turned on) in this guild's channels as context, how many, and whether to rewrite replies to edited messages
+`/ratelimit`: Limit how often Jeeves replies per guild, channel or user, or set a cooldown after each reply, for the guild or just this channel
+`/template`: Choose the chat template a local model's prompt is written in (`transcript`, `chatml`, `llama2`, `llama3`, `mistral`, `alpaca`), or define your own
+`/fallback`: Set the models Jeeves tries, in order, when his own fails (e.g. `gpt-3.5-turbo, local`)
+`/stream`: Have Jeeves post his replies as he writes them (local models only)
+`/tools`: List the tools Jeeves may use in his replies, or `enable` or `disable` one for this guild
+`/calc`, `/roll`, `/time`, `/convert`: Have Jeeves work out a sum, roll dice, tell the time in a time zone, or convert units
+`/knowledge`: `add` a text or markdown document for Jeeves to consult and cite when he replies, `remove` one by name, or `list` them
+`/usage`: `show` the tokens Jeeves has spent here and what they cost, by model, channel and user; `reset` the ledger; or list the `prices`
+`/quota`: `set` a `daily` or `monthly` cap in `tokens` or `dollars` on the `guild` or each `user`, `clear` caps, choose what happens `over` a cap (`refuse` or a model to fall back on), when to `warn` (e.g. `50,80`), send warnings to this channel with `alert`, or `list` it all
+`/attribution`: Set how a speaker's name is written into their message when it can't be sent as the message's name (default `{name}: {content}`)
+`/params`: Set a generation parameter (`max_tokens`, `temperature`, `top_p`, `presence_penalty`, `frequency_penalty`, `stop` as `a|b`, `seed`, `logit_bias` as `token:bias,...`) for this guild or just this channel; leave out the value to clear it
+`/respond`: Choose when Jeeves replies (`pinged`, `phrase` with a comma-separated list of phrases or `/regexes/`, `every`), for this guild or just this channel
 "#
     .to_string();
 
@@ -61,7 +91,9 @@ pub fn clear_conversation(
         .entry(channel_id.clone())
         .or_insert(vec![])
         .clear();
+    guild.summaries.remove(channel_id);
     set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+    forget_channel_memory(&guild_id, channel_id)?;
 
     send_message_to_discord(
         "Conversation history cleared.".to_string(),
@@ -143,6 +175,8 @@ pub fn leave_channel(
     )
 }
 
+/// Set the model for the whole guild, or for this channel only; `inherit` takes
+/// a channel back to the guild's.
 pub fn switch_model(
     our: &Address,
     bot: &BotId,
@@ -150,30 +184,30 @@ pub fn switch_model(
     interaction_id: String,
     interaction_token: String,
     guild_id: String,
-    _channel_id: String,
+    channel_id: String,
     data: InteractionData,
 ) -> anyhow::Result<()> {
-    let models: Vec<&str> = vec![
-        "local",
-        "gpt-3.5-turbo",
-        "gpt-4",
-        "gpt-4-1106-preview",
-        "gpt-4-turbo-preview",
-    ];
-    let Some(opts) = data.options else {
-        return Ok(());
-    };
-    let Some(opt) = opts.first() else {
-        return Ok(());
+    let models = registered_models();
+    let channel_only = get_option(&data, "scope").and_then(|v| v.as_str()) == Some("channel");
+    let Some(model) = get_option(&data, "model").and_then(|v| v.as_str()) else {
+        let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+            .unwrap_or(empty_state());
+        return send_message_to_discord(
+            format!(
+                "The models at your disposal, sir:\n{}",
+                describe_models(&state.models)
+            ),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
     };
-    if !models.contains(&opt.value.as_str().unwrap_or("")) {
+    let inherit = channel_only && model == "inherit";
+    if !inherit && !models.iter().any(|m| m == model) {
         send_message_to_discord(
-            format!(
-                "Invalid model: {}. Valid models are: {:?}",
-                opt.value.clone().to_string(),
-                models
-            )
-            .to_string(),
+            format!("Invalid model: {}. Valid models are: {:?}", model, models).to_string(),
             our,
             bot,
             discord_api_id,
@@ -183,18 +217,143 @@ pub fn switch_model(
         return Ok(());
     }
 
-    let model = opt.value.as_str().unwrap_or("").to_string();
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
     let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
         .unwrap_or(empty_state());
     let Some(guild) = state.guilds.get_mut(&guild_id) else {
         println!("jeeves: no guild for switch_model");
         return Ok(());
     };
-    guild.llm = model.clone();
+    // the settings in force have to suit the new model too
+    let mut candidate = guild.clone();
+    if inherit {
+        candidate.configure_channel(&channel_id, |c| c.llm = None);
+    } else if channel_only {
+        candidate.configure_channel(&channel_id, |c| c.llm = Some(model.to_string()));
+    } else {
+        candidate.llm = model.to_string();
+    }
+    if let Err(e) = validate_guild_gen_params(&state.models, &candidate) {
+        return send_message_to_discord(
+            format!(
+                "I'm afraid that won't do, sir: {}. Pray adjust the settings with /params first.",
+                e
+            ),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    }
+    let reply = if inherit {
+        guild.configure_channel(&channel_id, |c| c.llm = None);
+        format!(
+            "Very good, sir. This channel shall have the guild's model, {}.",
+            guild.llm
+        )
+    } else if channel_only {
+        guild.configure_channel(&channel_id, |c| c.llm = Some(model.to_string()));
+        format!("LLM has been changed to {} in this channel", model)
+    } else {
+        guild.llm = model.to_string();
+        format!("LLM has been changed to {}", model)
+    };
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+/// Set the system prompt Jeeves answers with, for the whole guild or this channel
+/// only. Without a prompt, show the one in force here.
+pub fn set_system_prompt(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let prompt = get_option(&data, "prompt")
+        .and_then(|v| v.as_str())
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty());
+    let channel_only = get_option(&data, "scope").and_then(|v| v.as_str()) == Some("channel");
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_system_prompt");
+        return Ok(());
+    };
+
+    let reply = match prompt.as_deref() {
+        None => format!(
+            "My instructions in this channel, sir:\n{}",
+            guild.system_prompt_for_channel(&channel_id)
+        ),
+        Some("inherit") if channel_only => {
+            guild.configure_channel(&channel_id, |c| c.system_prompt = None);
+            "Very good, sir. In this channel I shall be as I am elsewhere in the guild.".to_string()
+        }
+        Some("default") if !channel_only => {
+            guild.system_prompt = system_prompt().1;
+            "Very good, sir. I shall be myself again.".to_string()
+        }
+        Some(prompt) => {
+            if channel_only {
+                guild
+                    .configure_channel(&channel_id, |c| c.system_prompt = Some(prompt.to_string()));
+            } else {
+                guild.system_prompt = prompt.to_string();
+            }
+            format!(
+                "Very good, sir. I shall conduct myself accordingly in {}.",
+                if channel_only {
+                    "this channel"
+                } else {
+                    "this guild"
+                }
+            )
+        }
+    };
     set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
 
     send_message_to_discord(
-        format!("LLM has been changed to {}", model).to_string(),
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+/// The registry is the node owner's to change, from the UI; guilds may only look.
+pub fn list_models(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+) -> anyhow::Result<()> {
+    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    send_message_to_discord(
+        format!(
+            "The models at your disposal, sir:\n{}",
+            describe_models(&state.models)
+        ),
         our,
         bot,
         discord_api_id,
@@ -231,11 +390,57 @@ pub fn send_status(
 **Guild**: {}
 **Channels**: {}
 **Message Count (this channel)**: {}
-**Model**: {}"#,
+**Model (this channel)**: {}
+**Fallbacks**: {}
+**Last answered by**: {}
+**Responds (this channel)**: {}
+**Context**: {}
+**Generation (this channel)**: {}
+**Cooldown (this channel)**: {} seconds
+**System prompt (this channel)**: {}
+**Summary (this channel)**: {}"#,
         guild_id,
         format!("#{}", guild.our_channels.join(", #")),
         guild.message_log.get(&channel_id).unwrap_or(&vec![]).len(),
-        guild.llm
+        guild.model_for_channel(&channel_id),
+        if guild.fallback_models.is_empty() {
+            "(none)".to_string()
+        } else {
+            guild.fallback_models.join(" → ")
+        },
+        guild
+            .message_log
+            .get(&channel_id)
+            .and_then(|log| log.iter().rev().find_map(|m| m.model.clone()))
+            .unwrap_or("(nobody yet)".to_string()),
+        describe_response_schema(response_schema_for_channel(guild, &channel_id)),
+        format!(
+            "{} the last {} messages",
+            if guild.capture.enabled {
+                "all of"
+            } else {
+                "replies among"
+            },
+            guild.capture.window
+        ),
+        describe_gen_params(&effective_gen_params(guild, &channel_id)),
+        guild.cooldown_secs_for_channel(&channel_id),
+        if guild
+            .channel_configs
+            .get(&channel_id)
+            .and_then(|c| c.system_prompt.as_ref())
+            .is_some()
+        {
+            "its own"
+        } else {
+            "the guild's"
+        },
+        guild
+            .summaries
+            .get(&channel_id)
+            .filter(|s| !s.text.is_empty())
+            .map(|s| s.text.clone())
+            .unwrap_or("(nothing yet)".to_string())
     )
     .to_string();
 
@@ -265,11 +470,30 @@ pub fn create_guild_if_not_exists(
         id: guild_id.clone(),
         our_channels: vec![channel_id.clone()],
         message_log: HashMap::new(),
-        cooldown: 0,
+        summaries: HashMap::new(),
+        capture: CaptureSettings::default(),
+        regenerate_on_edit: false,
+        stream_replies: false,
+        cooldown_until: 0,
+        channel_cooldowns: HashMap::new(),
+        rate_limits: RateLimits::default(),
+        rate_buckets: RateBuckets::default(),
         debug: false,
-        llm: "gpt-3.5-turbo".to_string(),
+        llm: DEFAULT_MODEL.to_string(),
+        fallback_models: vec![],
+        embedder: Some(provider_for_model(&state.models, DEFAULT_MODEL)),
+        disabled_tools: vec![],
+        gen_params: GenerationParams::default(),
+        usage: UsageLedger::default(),
+        quotas: QuotaSettings::default(),
+        model_templates: HashMap::new(),
+        custom_templates: HashMap::new(),
+        attribution: DEFAULT_ATTRIBUTION.to_string(),
         system_prompt: system_prompt().1,
-        response_schema: BotResponseSchema::EveryMessage,
+        response_schema: BotResponseSchema::Pinged,
+        channel_configs: HashMap::new(),
+        channel_response_schemas: HashMap::new(),
+        channel_gen_params: HashMap::new(),
         listen_to_roles: vec![],
         ignore_roles: vec![],
         listen_to_users: vec![],
@@ -279,3 +503,1159 @@ pub fn create_guild_if_not_exists(
     set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
     Ok(())
 }
+
+pub fn get_option<'a>(data: &'a InteractionData, name: &str) -> Option<&'a serde_json::Value> {
+    data.options
+        .as_ref()?
+        .iter()
+        .find(|o| o.name == name)
+        .map(|o| &o.value)
+}
+
+pub fn set_response_schema(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let mode = get_option(&data, "mode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_lowercase();
+    let channel_only = get_option(&data, "scope").and_then(|v| v.as_str()) == Some("channel");
+
+    let schema = match mode.as_str() {
+        "inherit" if channel_only => None,
+        "pinged" => Some(BotResponseSchema::Pinged),
+        "every" => Some(BotResponseSchema::EveryMessage),
+        "phrase" => {
+            let phrases = get_option(&data, "phrases")
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            let case_sensitive = get_option(&data, "case_sensitive")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            let whole_word = get_option(&data, "whole_word")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true);
+            match parse_trigger_phrases(phrases, case_sensitive, whole_word) {
+                Ok(triggers) => Some(BotResponseSchema::WordOrPhrase(triggers)),
+                Err(e) => {
+                    return send_message_to_discord(
+                        format!("I am afraid I cannot do that, sir: {}", e),
+                        our,
+                        bot,
+                        discord_api_id,
+                        interaction_id,
+                        Some(interaction_token),
+                    );
+                }
+            }
+        }
+        _ => {
+            return send_message_to_discord(
+                format!(
+                    "Invalid mode: {}. Valid modes are: pinged, phrase, every, or inherit for a channel",
+                    mode
+                ),
+                our,
+                bot,
+                discord_api_id,
+                interaction_id,
+                Some(interaction_token),
+            );
+        }
+    };
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_response_schema");
+        return Ok(());
+    };
+    if channel_only {
+        guild.configure_channel(&channel_id, |c| c.response_schema = schema);
+    } else if let Some(schema) = schema {
+        guild.response_schema = schema;
+    }
+    let description = describe_response_schema(if channel_only {
+        response_schema_for_channel(guild, &channel_id)
+    } else {
+        &guild.response_schema
+    });
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        format!(
+            "Very good, sir. I shall respond {} in {}.",
+            description,
+            if channel_only {
+                "this channel"
+            } else {
+                "this guild"
+            }
+        ),
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn edit_access_list(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let action = get_option(&data, "action")
+        .and_then(|v| v.as_str())
+        .unwrap_or("show")
+        .to_string();
+    let list = get_option(&data, "list")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+    let id = get_option(&data, "role")
+        .or(get_option(&data, "user"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for edit_access_list");
+        return Ok(());
+    };
+
+    if action != "show" {
+        let Some(ids) = access_list_mut(guild, &list) else {
+            return send_message_to_discord(
+                format!(
+                    "Invalid list: {}. Valid lists are: listen_to_roles, ignore_roles, listen_to_users, ignore_users",
+                    list
+                ),
+                our,
+                bot,
+                discord_api_id,
+                interaction_id,
+                Some(interaction_token),
+            );
+        };
+        match (action.as_str(), id) {
+            ("add", Some(id)) => {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+            ("remove", Some(id)) => ids.retain(|i| i != &id),
+            ("clear", _) => ids.clear(),
+            _ => {
+                return send_message_to_discord(
+                    "Please give an action of add, remove, clear or show, and a role or user to add or remove."
+                        .to_string(),
+                    our,
+                    bot,
+                    discord_api_id,
+                    interaction_id,
+                    Some(interaction_token),
+                );
+            }
+        }
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+    }
+
+    let Some(guild) = state.guilds.get(&guild_id) else {
+        return Ok(());
+    };
+    send_message_to_discord(
+        describe_access_lists(guild),
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_rate_limit(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let scope = get_option(&data, "scope")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+    let capacity = get_option(&data, "capacity").and_then(|v| v.as_u64());
+    let per_minute = get_option(&data, "per_minute").and_then(|v| v.as_f64());
+    let Ok(capacity) = capacity.map(u32::try_from).transpose() else {
+        return send_message_to_discord(
+            format!(
+                "I fear that is rather more than I can count to, sir. Kindly give a `capacity` of at most {}.",
+                u32::MAX
+            ),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    };
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_rate_limit");
+        return Ok(());
+    };
+
+    if scope == "cooldown" {
+        let this_channel = get_option(&data, "this_channel")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let reply = if this_channel {
+            // without a length, the channel goes back to the guild's cooldown
+            let secs = capacity;
+            guild.configure_channel(&channel_id, |c| c.cooldown_secs = secs);
+            match secs {
+                Some(secs) => {
+                    let until = now_secs() as u64 + secs as u64;
+                    if let Some(left) = guild.channel_cooldowns.get_mut(&channel_id) {
+                        *left = (*left).min(until);
+                    }
+                    format!(
+                        "Very good, sir. I shall pause {} seconds after each reply in this channel.",
+                        secs
+                    )
+                }
+                None => {
+                    guild.channel_cooldowns.remove(&channel_id);
+                    "Very good, sir. This channel shall keep the guild's cooldown.".to_string()
+                }
+            }
+        } else {
+            let secs = capacity.unwrap_or(0);
+            guild.rate_limits.cooldown_secs = secs;
+            guild.cooldown_until = guild.cooldown_until.min(now_secs() as u64 + secs as u64);
+            format!(
+                "Very good, sir. I shall pause {} seconds after each reply.",
+                secs
+            )
+        };
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+        return send_message_to_discord(
+            reply,
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    }
+
+    let limit = match (capacity, per_minute) {
+        (Some(0), _) | (None, _) => None,
+        (Some(capacity), Some(per_minute)) if per_minute > 0.0 => Some(RateLimit {
+            capacity,
+            refill_per_minute: per_minute,
+        }),
+        _ => {
+            return send_message_to_discord(
+                "Please give a positive `per_minute` refill rate, or a `capacity` of 0 to remove the limit."
+                    .to_string(),
+                our,
+                bot,
+                discord_api_id,
+                interaction_id,
+                Some(interaction_token),
+            );
+        }
+    };
+    let description = match &limit {
+        Some(l) => format!(
+            "at most {} replies at once, refilling {} per minute",
+            l.capacity, l.refill_per_minute
+        ),
+        None => "no limit".to_string(),
+    };
+    match scope.as_str() {
+        "guild" => guild.rate_limits.guild = limit,
+        "channel" => guild.rate_limits.channel = limit,
+        "user" => guild.rate_limits.user = limit,
+        _ => {
+            return send_message_to_discord(
+                format!(
+                    "Invalid scope: {}. Valid scopes are: guild, channel, user, cooldown",
+                    scope
+                ),
+                our,
+                bot,
+                discord_api_id,
+                interaction_id,
+                Some(interaction_token),
+            );
+        }
+    }
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        format!("Very good, sir. Each {} now has {}.", scope, description),
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_capture(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_capture");
+        return Ok(());
+    };
+    if let Some(enabled) = get_option(&data, "enabled").and_then(|v| v.as_bool()) {
+        guild.capture.enabled = enabled;
+    }
+    if let Some(window) = get_option(&data, "window").and_then(|v| v.as_u64()) {
+        guild.capture.window = (window as usize).max(1);
+    }
+    if let Some(regenerate) = get_option(&data, "regenerate_on_edit").and_then(|v| v.as_bool()) {
+        guild.regenerate_on_edit = regenerate;
+    }
+    let msg = if guild.capture.enabled {
+        format!(
+            "Very good, sir. I shall keep the last {} messages of each channel in mind.",
+            guild.capture.window
+        )
+    } else {
+        format!(
+            "Very good, sir. I shall only keep in mind the last {} messages addressed to me.",
+            guild.capture.window
+        )
+    };
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        msg,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_chat_template(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let name = get_option(&data, "name")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .trim()
+        .to_string();
+    let part = |key: &str| {
+        get_option(&data, key)
+            .and_then(|v| v.as_str())
+            .map(unescape_template_part)
+    };
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_chat_template");
+        return Ok(());
+    };
+    let model = get_option(&data, "model")
+        .and_then(|v| v.as_str())
+        .map(|m| m.to_string())
+        .unwrap_or(guild.model_for_channel(&channel_id));
+
+    // giving a user turn format defines (or redefines) a template of our own
+    if let Some(user) = part("user") {
+        if builtin_templates().iter().any(|t| t.name == name) {
+            return send_message_to_discord(
+                format!(
+                    "I fear {} is one of my own templates, sir. Might I suggest another name?",
+                    name
+                ),
+                our,
+                bot,
+                discord_api_id,
+                interaction_id,
+                Some(interaction_token),
+            );
+        }
+        let template = ChatTemplate {
+            name: name.clone(),
+            bos: part("bos").unwrap_or_default(),
+            system: part("system").unwrap_or("{content}\n\n".to_string()),
+            user,
+            assistant: part("assistant").unwrap_or("{content}\n".to_string()),
+            generation: part("generation").unwrap_or_default(),
+            stop: part("stop")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        };
+        guild.custom_templates.insert(name.clone(), template);
+    }
+
+    if find_template(guild, &name).is_none() {
+        let mut names = builtin_templates()
+            .into_iter()
+            .map(|t| t.name)
+            .collect::<Vec<String>>();
+        names.extend(guild.custom_templates.keys().cloned());
+        return send_message_to_discord(
+            format!(
+                "Invalid template: {}. Valid templates are: {:?}",
+                name, names
+            ),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    }
+    guild.model_templates.insert(model.clone(), name.clone());
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        format!(
+            "Very good, sir. {} shall be prompted in the {} format.",
+            model, name
+        ),
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_fallback_models(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let models = get_option(&data, "models")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty() && m != "none")
+        .collect::<Vec<String>>();
+    let known = registered_models();
+    if let Some(invalid) = models.iter().find(|m| !known.contains(m)) {
+        return send_message_to_discord(
+            format!("Invalid model: {}. Valid models are: {:?}", invalid, known),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    }
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_fallback_models");
+        return Ok(());
+    };
+    let mut candidate = guild.clone();
+    candidate.fallback_models = models.clone();
+    if let Err(e) = validate_guild_gen_params(&state.models, &candidate) {
+        return send_message_to_discord(
+            format!(
+                "I'm afraid that won't do, sir: {}. Pray adjust the settings with /params first.",
+                e
+            ),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    }
+    guild.fallback_models = models.clone();
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        if models.is_empty() {
+            "Very good, sir. I shall rely on my model alone.".to_string()
+        } else {
+            format!(
+                "Very good, sir. Should my model fail me, I shall turn to {}.",
+                models.join(", then ")
+            )
+        },
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_streaming(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let enabled = get_option(&data, "enabled")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_streaming");
+        return Ok(());
+    };
+    guild.stream_replies = enabled;
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        if enabled {
+            "Very good, sir. Where my model permits, you shall see my replies as I compose them."
+        } else {
+            "Very good, sir. I shall present my replies only once they are complete."
+        }
+        .to_string(),
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_gen_params(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let name = get_option(&data, "setting")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    let value = get_option(&data, "value").and_then(|v| v.as_str());
+    let this_channel = get_option(&data, "this_channel")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_gen_params");
+        return Ok(());
+    };
+
+    let mut params = if this_channel {
+        guild
+            .channel_configs
+            .get(&channel_id)
+            .map(|c| c.gen_params.clone())
+            .unwrap_or_default()
+    } else {
+        guild.gen_params.clone()
+    };
+    if let Err(e) = set_gen_param(&mut params, &name, value) {
+        return send_message_to_discord(
+            format!("{}. Valid settings are: {}", e, GEN_PARAM_NAMES.join(", ")),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    }
+
+    // every model the guild may fall back to has to accept the result
+    let mut candidate = guild.clone();
+    if this_channel {
+        candidate.configure_channel(&channel_id, |c| c.gen_params = params.clone());
+    } else {
+        candidate.gen_params = params.clone();
+    }
+    let effective = effective_gen_params(&candidate, &channel_id);
+    if let Err(e) = validate_guild_gen_params(&state.models, &candidate) {
+        return send_message_to_discord(
+            format!("I'm afraid that won't do, sir: {}.", e),
+            our,
+            bot,
+            discord_api_id,
+            interaction_id,
+            Some(interaction_token),
+        );
+    }
+
+    if this_channel {
+        guild.configure_channel(&channel_id, |c| c.gen_params = params);
+    } else {
+        guild.gen_params = params;
+    }
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        format!(
+            "Very good, sir. In this channel I shall now write with {}.",
+            describe_gen_params(&effective)
+        ),
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn edit_tools(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let action = get_option(&data, "action")
+        .and_then(|v| v.as_str())
+        .unwrap_or("list")
+        .to_lowercase();
+    let name = get_option(&data, "tool")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .trim()
+        .to_string();
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for edit_tools");
+        return Ok(());
+    };
+
+    let reply = match action.as_str() {
+        "list" => format!("My tools, sir:\n{}", describe_tools(guild)),
+        "enable" | "disable" => {
+            if find_tool(&name).is_none() {
+                format!(
+                    "I have no tool called `{}`, sir. I have:\n{}",
+                    name,
+                    describe_tools(guild)
+                )
+            } else {
+                guild.disabled_tools.retain(|t| t != &name);
+                if action == "disable" {
+                    guild.disabled_tools.push(name.clone());
+                }
+                set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+                format!(
+                    "Very good, sir. I shall {} `{}` in this guild.",
+                    if action == "enable" {
+                        "make use of"
+                    } else {
+                        "do without"
+                    },
+                    name
+                )
+            }
+        }
+        _ => "Please give an action of list, enable or disable.".to_string(),
+    };
+
+    send_message_to_discord(
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+/// Run a tool as its own slash command, with the command's options as its arguments.
+pub fn run_tool_command(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    tool: ToolSpec,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    if let Some(guild) = state.guilds.get(&guild_id) {
+        if guild.disabled_tools.iter().any(|t| t == tool.name) {
+            return send_message_to_discord(
+                format!(
+                    "I'm afraid `{}` has been put away in this guild, sir.",
+                    tool.name
+                ),
+                our,
+                bot,
+                discord_api_id,
+                interaction_id,
+                Some(interaction_token),
+            );
+        }
+    }
+
+    let mut args = serde_json::Map::new();
+    for option in data.options.unwrap_or_default() {
+        if !option.value.is_null() {
+            args.insert(option.name, option.value);
+        }
+    }
+    let context = ToolContext {
+        guild_id,
+        channel_id,
+    };
+    let reply = match (tool.run)(&context, serde_json::Value::Object(args)) {
+        Ok(result) => result,
+        Err(e) => format!("I'm afraid I couldn't, sir: {}.", e),
+    };
+
+    send_message_to_discord(
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+/// A text file attached to a slash command, if it is one we can read.
+fn text_attachment(data: &InteractionData, option: &str) -> anyhow::Result<Attachment> {
+    let Some(attachment_id) = get_option(data, option).and_then(|v| v.as_str()) else {
+        return Err(anyhow::anyhow!("no file attached"));
+    };
+    let Some(attachment) = data
+        .resolved
+        .as_ref()
+        .and_then(|r| r.attachments.as_ref())
+        .and_then(|a| a.get(attachment_id))
+    else {
+        return Err(anyhow::anyhow!("the attachment was not sent along"));
+    };
+    let is_text = attachment
+        .content_type
+        .as_ref()
+        .map(|t| t.starts_with("text/"))
+        .unwrap_or(false)
+        || attachment.filename.ends_with(".txt")
+        || attachment.filename.ends_with(".md");
+    if !is_text {
+        return Err(anyhow::anyhow!("I can only read plain text and markdown"));
+    }
+    if attachment.size as usize > KNOWLEDGE_MAX_DOCUMENT_BYTES {
+        return Err(anyhow::anyhow!(
+            "the file is larger than {} bytes",
+            KNOWLEDGE_MAX_DOCUMENT_BYTES
+        ));
+    }
+    Ok(attachment.clone())
+}
+
+/// A document for `/knowledge add` has been downloaded, or `None` if it never
+/// will be: file it, and say how that went.
+pub fn handle_document_fetched(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    fetch: DocumentFetch,
+    body: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get(&fetch.guild_id) else {
+        println!("jeeves: no guild for a fetched document");
+        return Ok(());
+    };
+    let outcome = body
+        .ok_or(anyhow::anyhow!("the download timed out"))
+        .and_then(downloaded_bytes)
+        .and_then(|bytes| {
+            String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("the file is not UTF-8 text"))
+        })
+        .and_then(|text| add_document(&state.models, guild, &fetch.name, &text));
+    let reply = match outcome {
+        Ok(passages) => format!(
+            "I have `{}` in hand, sir ({} passages), and shall refer to it henceforth.",
+            fetch.name, passages
+        ),
+        Err(e) => format!("I'm afraid I could not file `{}`, sir: {}.", fetch.name, e),
+    };
+    send_message_to_discord(reply, our, bot, discord_api_id, fetch.channel_id, None)
+}
+
+pub fn manage_knowledge(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let action = get_option(&data, "action")
+        .and_then(|v| v.as_str())
+        .unwrap_or("list")
+        .to_lowercase();
+    let name = get_option(&data, "name")
+        .and_then(|v| v.as_str())
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty());
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+
+    let reply = match action.as_str() {
+        "list" => format!(
+            "The documents I consult in this guild, sir:\n{}",
+            describe_knowledge(&guild_id)
+        ),
+        "add" => match text_attachment(&data, "file") {
+            Ok(attachment) => {
+                let name = name.unwrap_or(attachment.filename.clone());
+                let fetch = DocumentFetch {
+                    guild_id: guild_id.clone(),
+                    channel_id,
+                    name: name.clone(),
+                };
+                match request_download(&attachment.url, &ResponseContext::DocumentFetched(fetch)) {
+                    Ok(()) => format!("Very good, sir. I shall fetch `{}` directly.", name),
+                    Err(e) => format!("I'm afraid I could not fetch `{}`, sir: {}.", name, e),
+                }
+            }
+            Err(e) => format!("I'm afraid I could not read that, sir: {}.", e),
+        },
+        "remove" => match name {
+            None => "Please tell me the name of the document to remove, sir.".to_string(),
+            Some(name) => {
+                if remove_document(&guild_id, &name)? {
+                    format!("Very good, sir. I have put `{}` away.", name)
+                } else {
+                    format!(
+                        "I have no document called `{}`, sir. I have:\n{}",
+                        name,
+                        describe_knowledge(&guild_id)
+                    )
+                }
+            }
+        },
+        _ => "Please give an action of list, add or remove.".to_string(),
+    };
+
+    send_message_to_discord(
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn manage_usage(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let action = get_option(&data, "action")
+        .and_then(|v| v.as_str())
+        .unwrap_or("show")
+        .to_lowercase();
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+
+    let reply = match action.as_str() {
+        "show" => match state.guilds.get(&guild_id) {
+            Some(guild) => format!(
+                "What I have spent in this guild, sir:\n{}",
+                describe_usage(&guild.usage)
+            ),
+            None => "I have spent nothing here, sir.".to_string(),
+        },
+        "reset" => {
+            if let Some(guild) = state.guilds.get_mut(&guild_id) {
+                guild.usage = UsageLedger::default();
+            }
+            set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+            "Very good, sir. The ledger is cleared.".to_string()
+        }
+        "prices" => format!(
+            "The prices I reckon with, sir:\n{}",
+            describe_prices(&state.models)
+        ),
+        _ => "Please give an action of show, reset or prices.".to_string(),
+    };
+
+    send_message_to_discord(
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_quotas(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let option = |name: &str| {
+        get_option(&data, name)
+            .and_then(|v| v.as_str())
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty())
+    };
+    let action = option("action").unwrap_or("list".to_string());
+    let scope = match option("scope").as_deref() {
+        Some("user") => Some(QuotaScope::User),
+        Some("guild") => Some(QuotaScope::Guild),
+        _ => None,
+    };
+    let period = match option("period").as_deref() {
+        Some("daily") => Some(QuotaPeriod::Daily),
+        Some("monthly") => Some(QuotaPeriod::Monthly),
+        _ => None,
+    };
+    let unit = match option("unit").as_deref() {
+        Some("tokens") => Some(QuotaUnit::Tokens),
+        Some("dollars") => Some(QuotaUnit::Dollars),
+        _ => None,
+    };
+    let value = get_option(&data, "value")
+        .and_then(|v| v.as_str())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+
+    create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        println!("jeeves: no guild for set_quotas");
+        return Ok(());
+    };
+    let settings = &mut guild.quotas;
+
+    let reply = match action.as_str() {
+        "list" => format!("The household budget, sir:\n{}", describe_quotas(settings)),
+        "set" => match (scope, period, unit, value.map(|v| v.parse::<f64>())) {
+            (Some(scope), Some(period), Some(unit), Some(Ok(limit))) if limit >= 0.0 => {
+                settings
+                    .quotas
+                    .retain(|q| !(q.scope == scope && q.period == period && q.unit == unit));
+                settings.quotas.push(Quota {
+                    scope,
+                    period,
+                    unit,
+                    limit,
+                });
+                format!(
+                    "Very good, sir. The budget now stands thus:\n{}",
+                    describe_quotas(settings)
+                )
+            }
+            _ => "Please give a scope (`guild` or `user`), a period (`daily` or `monthly`), a unit (`tokens` or `dollars`) and the limit as the value, sir.".to_string(),
+        },
+        "clear" => {
+            settings.quotas.retain(|q| {
+                !(scope.map(|s| s == q.scope).unwrap_or(true)
+                    && period.map(|p| p == q.period).unwrap_or(true)
+                    && unit.map(|u| u == q.unit).unwrap_or(true))
+            });
+            format!(
+                "Very good, sir. The budget now stands thus:\n{}",
+                describe_quotas(settings)
+            )
+        }
+        "over" => match value {
+            Some(v) if v.to_lowercase() == "refuse" => {
+                settings.over_quota = OverQuota::Refuse;
+                "Very good, sir. Once the allowance is spent, I shall politely decline.".to_string()
+            }
+            Some(model) if registered_models().contains(&model) => {
+                settings.over_quota = OverQuota::Downgrade(model.clone());
+                format!(
+                    "Very good, sir. Once the allowance is spent, I shall make do with `{}`.",
+                    model
+                )
+            }
+            _ => format!(
+                "Please give `refuse`, or one of these models to fall back on: {}",
+                registered_models().join(", ")
+            ),
+        },
+        "warn" => {
+            let thresholds = value
+                .unwrap_or_default()
+                .split(',')
+                .map(|t| t.trim().trim_end_matches('%').parse::<u32>())
+                .collect::<Result<Vec<u32>, _>>();
+            match thresholds {
+                Ok(thresholds) if thresholds.iter().all(|t| *t > 0) => {
+                    settings.warn_at = thresholds;
+                    format!(
+                        "Very good, sir. I shall warn at {}.",
+                        settings
+                            .warn_at
+                            .iter()
+                            .map(|t| format!("{}%", t))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                }
+                _ => "Please give the percentages to warn at, like `50,80,100`.".to_string(),
+            }
+        }
+        "alert" => {
+            if value.map(|v| v.to_lowercase() == "off").unwrap_or(false) {
+                settings.alert_channel = None;
+                "Very good, sir. I shall warn wherever the money is spent.".to_string()
+            } else {
+                settings.alert_channel = Some(channel_id.clone());
+                "Very good, sir. I shall bring any warnings about the budget to this channel."
+                    .to_string()
+            }
+        }
+        _ => "Please give an action of list, set, clear, over, warn or alert.".to_string(),
+    };
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    send_message_to_discord(
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
+
+pub fn set_attribution(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    interaction_id: String,
+    interaction_token: String,
+    guild_id: String,
+    channel_id: String,
+    data: InteractionData,
+) -> anyhow::Result<()> {
+    let format = get_option(&data, "format")
+        .and_then(|v| v.as_str())
+        .map(unescape_template_part)
+        .filter(|f| !f.trim().is_empty())
+        .unwrap_or(DEFAULT_ATTRIBUTION.to_string());
+
+    let reply = if !format.contains("{name}") || !format.contains("{content}") {
+        "The format must have both `{name}` and `{content}` in it, sir.".to_string()
+    } else {
+        create_guild_if_not_exists(&Some(guild_id.clone()), &channel_id)?;
+        let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+            .unwrap_or(empty_state());
+        let Some(guild) = state.guilds.get_mut(&guild_id) else {
+            println!("jeeves: no guild for set_attribution");
+            return Ok(());
+        };
+        guild.attribution = format.clone();
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+        format!(
+            "Very good, sir. Where I can't otherwise say who is speaking, I shall write `{}`.",
+            format
+        )
+    };
+
+    send_message_to_discord(
+        reply,
+        our,
+        bot,
+        discord_api_id,
+        interaction_id,
+        Some(interaction_token),
+    )
+}
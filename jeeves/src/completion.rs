@@ -0,0 +1,423 @@
+use crate::consts::*;
+use crate::context::*;
+use crate::conversation::*;
+use crate::discord::*;
+use crate::empty_state;
+use crate::errors::*;
+use crate::genparams::*;
+use crate::knowledge::*;
+use crate::memory::*;
+use crate::models::*;
+use crate::providers::*;
+use crate::quotas::*;
+use crate::ratelimit::*;
+use crate::speakers::*;
+use crate::streaming::*;
+use crate::summary::*;
+use crate::templates::*;
+use crate::tools::*;
+use crate::types::*;
+use crate::usage::*;
+use crate::vision::*;
+use discord_api::BotId;
+use kinode_process_lib::{
+    get_typed_state, println, set_state, timer::set_timer, Address, ProcessId, Request,
+};
+
+/// Asks for a completion of the channel's conversation, or only the part of it up to
+/// and including the message being replied to if we are regenerating. The answer
+/// arrives later as a Response carrying `pending` in its context.
+pub fn request_completion_for_guild_channel(pending: PendingReply) -> anyhow::Result<()> {
+    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get(&pending.guild_id) else {
+        return Ok(());
+    };
+    if !guild.our_channels.contains(&pending.channel_id) {
+        return Ok(());
+    }
+    let Some(memories) = pending.memories.clone() else {
+        return request_recall(&state.models, guild, pending);
+    };
+
+    let mut system_prompt = system_prompt_with_summary(guild, &pending.channel_id);
+    if !memories.is_empty() {
+        system_prompt.push_str(&format!(
+            "\n\nFrom earlier in this channel, which may be relevant:\n{}",
+            memories.join("\n---\n")
+        ));
+    }
+    if !pending.references.is_empty() {
+        system_prompt.push_str(&format!("\n\n{}", describe_references(&pending.references)));
+    }
+    let mut messages: Vec<(Speaker, String)> = vec![(Speaker::System, system_prompt)];
+    // the message being answered, if it came with images
+    let mut answered = None;
+    for msg in guild
+        .message_log
+        .get(&pending.channel_id)
+        .unwrap_or(&vec![])
+        .clone()
+    {
+        let message = (
+            msg.speaker(),
+            format!("{}{}", msg.content, describe_images(&msg.images)),
+        );
+        if msg.id.as_ref() == Some(&pending.message_id) && !msg.images.is_empty() {
+            answered = Some((message.clone(), msg.images.clone()));
+        }
+        messages.push(message);
+        if pending.regenerate && msg.id.as_ref() == Some(&pending.message_id) {
+            break;
+        }
+    }
+
+    let Some(model) = reply_chain(guild, &pending)
+        .get(pending.chain_index)
+        .cloned()
+    else {
+        return Err(anyhow::anyhow!("no model left to try"));
+    };
+    let params = effective_gen_params(guild, &pending.channel_id);
+    if let Some((_, images)) = &answered {
+        // the reply is asked for again once the images are in
+        if supports_vision(&state.models, &model)
+            && cached_images(&pending.message_id).is_none()
+            && fetch_images(&pending, images)
+        {
+            return Ok(());
+        }
+    }
+    let tool_use = if supports_tools(&state.models, &model) {
+        tool_use_for(guild, &pending)
+    } else {
+        None
+    };
+    let image_tokens = match &answered {
+        Some((_, images)) if supports_vision(&state.models, &model) => {
+            images.len() * VISION_IMAGE_TOKENS
+        }
+        _ => 0,
+    };
+    // each time the model says the conversation is too long, offer it half as much
+    let budget = (context_length(&state.models, &model) >> pending.context_shrinks)
+        .saturating_sub(reply_tokens(&params))
+        .saturating_sub(tool_use.as_ref().map(estimate_tool_tokens).unwrap_or(0))
+        .saturating_sub(image_tokens);
+    let messages = fit_to_budget(messages, budget);
+    let images = match answered {
+        Some((message, _)) if supports_vision(&state.models, &model) => messages
+            .iter()
+            .rposition(|m| m == &message)
+            .map(|index| MessageImages {
+                index,
+                images: cached_images(&pending.message_id).unwrap_or_default(),
+            })
+            .filter(|i| !i.images.is_empty()),
+        _ => None,
+    };
+    let template = template_for_model(guild, &model);
+    let provider = provider_for_model(&state.models, &model);
+    let stream = guild.stream_replies && supports_streaming(&provider);
+    request_chat_completion(
+        messages,
+        images,
+        model,
+        provider,
+        &template,
+        &guild.attribution,
+        &params,
+        tool_use,
+        stream,
+        pending,
+    )
+}
+
+/// The channel's chosen model followed by the guild's fallbacks, in the order to try them.
+pub fn model_chain(guild: &GuildInfo, channel_id: &String) -> Vec<String> {
+    let mut model = guild.model_for_channel(channel_id);
+    if model.len() == 0 {
+        model = DEFAULT_MODEL.to_string();
+    }
+    let mut chain = vec![model];
+    for fallback in &guild.fallback_models {
+        if !chain.contains(fallback) {
+            chain.push(fallback.clone());
+        }
+    }
+    chain
+}
+
+/// The models to try for this reply: the channel's chain, unless its spending is
+/// capped and it must make do with a cheaper one.
+pub fn reply_chain(guild: &GuildInfo, pending: &PendingReply) -> Vec<String> {
+    match &pending.downgrade_to {
+        Some(model) => vec![model.clone()],
+        None => model_chain(guild, &pending.channel_id),
+    }
+}
+
+pub fn request_chat_completion(
+    messages: Vec<(Speaker, String)>,
+    images: Option<MessageImages>,
+    model: String,
+    provider: Provider,
+    template: &ChatTemplate,
+    attribution: &str,
+    params: &GenerationParams,
+    tool_use: Option<ToolUse>,
+    stream: bool,
+    pending: PendingReply,
+) -> anyhow::Result<()> {
+    let pending = pending.with_model(model.clone());
+    let stream = register_stream(&pending, stream);
+    let body = build_chat_request(
+        &provider,
+        &model,
+        &messages,
+        images,
+        template,
+        attribution,
+        params,
+        tool_use,
+        stream,
+    )?;
+    Request::new()
+        .target(Address::new("our", provider_process(&provider)))
+        .body(body)
+        .context(serde_json::to_vec(&ResponseContext::Completion(pending))?)
+        .expects_response(LLM_TIMEOUT_SECS)
+        .send()
+}
+
+/// The model's reply, cleaned of any speaker tags it has echoed. `turn_tags` are
+/// how the others' turns in the conversation begin.
+pub fn parse_completion(
+    provider: &Provider,
+    body: &[u8],
+    turn_tags: &[String],
+) -> anyhow::Result<String> {
+    let completion = parse_chat_response(provider, body)?;
+    let t = clean_reply(&completion, turn_tags);
+    println!("jeeves says: {}", t);
+    Ok(t)
+}
+
+/// Deliver a completion we asked for to the channel it was meant for.
+pub fn handle_completion_response(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    pending: PendingReply,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    record_usage(
+        &pending.guild_id,
+        &pending.channel_id,
+        pending.user_id.as_ref(),
+        &pending.model,
+        body,
+    );
+    for (channel_id, warning) in take_quota_warnings(&pending.guild_id, &pending.channel_id) {
+        send_message_to_discord(warning, our, bot, discord_api_id, channel_id, None)?;
+    }
+    if let Some(message) = tool_call_message(body) {
+        let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+            .unwrap_or(empty_state());
+        let Some(guild) = state.guilds.get(&pending.guild_id) else {
+            return Ok(());
+        };
+        return match handle_tool_calls(guild, pending.clone(), message) {
+            Ok(()) => Ok(()),
+            Err(e) => handle_completion_failure(
+                our,
+                bot,
+                discord_api_id,
+                pending,
+                JeevesError::from_anyhow(&e),
+            ),
+        };
+    }
+    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let turn_tags = state
+        .guilds
+        .get(&pending.guild_id)
+        .map(|g| {
+            let speakers = channel_speakers(g, &pending.channel_id);
+            turn_tags(&state.models, g, &pending.model, &speakers)
+        })
+        .unwrap_or_default();
+    let provider = provider_for_model(&state.models, &pending.model);
+    let completion = match parse_completion(&provider, body, &turn_tags) {
+        Ok(completion) => completion,
+        Err(e) => {
+            println!(
+                "jeeves: unreadable completion: {}",
+                String::from_utf8_lossy(&body[..body.len().min(300)])
+            );
+            let error = JeevesError::from_response(&e, body);
+            return handle_completion_failure(our, bot, discord_api_id, pending, error);
+        }
+    };
+    println!(
+        "jeeves: got completion from {}: {}",
+        pending.model, completion
+    );
+
+    let text = if pending.regenerate {
+        format!("(Revised, in light of your amendment, sir.) {}", completion)
+    } else {
+        completion.clone()
+    };
+    forget_images(&pending.message_id);
+    // the reply is logged even if Discord is slow to take it: it has been paid
+    // for, and its delivery is retried with the same text
+    match finish_stream(our, bot, discord_api_id, &pending, text.clone()) {
+        Ok(true) => {}
+        Ok(false) => deliver_reply(text, our, bot, discord_api_id, pending.channel_id.clone())?,
+        Err(e) => {
+            // whatever became of the placeholder, the reply itself must get through
+            println!("jeeves: could not finish the stream: {}", e);
+            deliver_reply(text, our, bot, discord_api_id, pending.channel_id.clone())?;
+        }
+    }
+
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&pending.guild_id) else {
+        return Ok(());
+    };
+    match reply_to(guild, &pending.channel_id, &pending.message_id) {
+        Some(index) if pending.regenerate => {
+            if let Some(log) = guild.message_log.get_mut(&pending.channel_id) {
+                log[index].content = completion;
+                log[index].model = Some(pending.model.clone());
+            }
+        }
+        _ => {
+            let dropped = push_utterance(
+                guild,
+                &pending.channel_id,
+                Utterance {
+                    id: None,
+                    username: "Jeeves".to_string(),
+                    content: completion,
+                    in_reply_to: Some(pending.message_id.clone()),
+                    model: Some(pending.model.clone()),
+                    images: vec![],
+                    from_bot: true,
+                },
+            );
+            start_cooldown(guild, &pending.channel_id);
+            remember_utterances(&state.models, guild, &pending.channel_id, dropped)?;
+        }
+    }
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+    maybe_summarize(&pending.guild_id, &pending.channel_id)
+}
+
+/// Do what `error` calls for: wait and retry the same model, retry it with less
+/// of the conversation, or move on to the next model in the guild's chain. Only
+/// when there is nothing left to try do we apologize to the channel.
+pub fn handle_completion_failure(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    pending: PendingReply,
+    error: JeevesError,
+) -> anyhow::Result<()> {
+    println!(
+        "jeeves: completion from {} failed (attempt {}): {}",
+        pending.model, pending.attempt, error
+    );
+
+    match error.retry_policy(pending.attempt) {
+        RetryPolicy::Backoff(ms) if pending.attempt < LLM_MAX_RETRIES => {
+            let retry = PendingReply {
+                attempt: pending.attempt + 1,
+                ..pending
+            };
+            set_timer(
+                ms,
+                Some(serde_json::to_vec(&ResponseContext::RetryCompletion(
+                    retry,
+                ))?),
+            );
+            return Ok(());
+        }
+        RetryPolicy::ShrinkContext if pending.context_shrinks < MAX_CONTEXT_SHRINKS => {
+            let retry = PendingReply {
+                context_shrinks: pending.context_shrinks + 1,
+                ..pending
+            };
+            return match request_completion_for_guild_channel(retry.clone()) {
+                Ok(()) => Ok(()),
+                Err(e) => handle_completion_failure(
+                    our,
+                    bot,
+                    discord_api_id,
+                    retry,
+                    JeevesError::from_anyhow(&e),
+                ),
+            };
+        }
+        RetryPolicy::GiveUp => {
+            return apologize(our, bot, discord_api_id, &pending, &error);
+        }
+        _ => {}
+    }
+
+    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let chain_len = state
+        .guilds
+        .get(&pending.guild_id)
+        .map(|g| reply_chain(g, &pending).len())
+        .unwrap_or(0);
+    if pending.chain_index + 1 < chain_len {
+        let next = PendingReply {
+            chain_index: pending.chain_index + 1,
+            attempt: 0,
+            context_shrinks: 0,
+            ..pending.clone()
+        };
+        println!("jeeves: falling back from {}", pending.model);
+        return match request_completion_for_guild_channel(next.clone()) {
+            Ok(()) => Ok(()),
+            Err(e) => handle_completion_failure(
+                our,
+                bot,
+                discord_api_id,
+                next,
+                JeevesError::from_anyhow(&e),
+            ),
+        };
+    }
+
+    apologize(our, bot, discord_api_id, &pending, &error)
+}
+
+fn apologize(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    pending: &PendingReply,
+    error: &JeevesError,
+) -> anyhow::Result<()> {
+    forget_images(&pending.message_id);
+    let text = error.user_message();
+    match finish_stream(our, bot, discord_api_id, pending, text.clone()) {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(e) => println!("jeeves: could not finish the stream: {}", e),
+    }
+    send_message_to_discord(
+        text,
+        our,
+        bot,
+        discord_api_id,
+        pending.channel_id.clone(),
+        None,
+    )
+}
@@ -2,3 +2,49 @@ pub const BOT_APPLICATION_ID: &str = include_str!("../.bot_application_id");
 pub const BOT_TOKEN: &str = include_str!("../.bot_token");
 pub const OPENAI_API_KEY: &str = include_str!("../.openai_api_key");
 pub const ICON: &str = include_str!("./icon");
+/// The model a guild starts with.
+pub const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+pub const LLM_TIMEOUT_SECS: u64 = 30;
+pub const LLM_MAX_RETRIES: u32 = 2;
+/// Times to halve the conversation for a model that says it is too long.
+pub const MAX_CONTEXT_SHRINKS: u32 = 2;
+pub const LLM_RETRY_BACKOFF_MS: u64 = 2000;
+pub const STREAM_EDIT_INTERVAL_MS: u64 = 1500;
+/// Times to try posting a finished reply again before it is given up for lost.
+pub const DISCORD_MAX_RETRIES: u32 = 3;
+pub const DISCORD_RETRY_BACKOFF_MS: u64 = 1000;
+/// Room kept free in the context window for the reply.
+pub const MAX_REPLY_TOKENS: usize = 900;
+/// Once a channel's log is longer than this, its older part is summarized.
+pub const SUMMARIZE_AFTER_MESSAGES: usize = 40;
+/// How many of the newest messages stay in the log, word for word, after summarizing.
+pub const SUMMARY_KEEP_RECENT: usize = 12;
+/// Times the model may call tools before it has to answer.
+pub const MAX_TOOL_ROUNDS: u32 = 4;
+pub const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+/// Its list price, in US dollars per million tokens.
+pub const OPENAI_EMBEDDING_PRICE_PER_MILLION: f64 = 0.02;
+/// Consecutive utterances stored together as one memory.
+pub const MEMORY_SNIPPET_UTTERANCES: usize = 4;
+/// Memories recalled for each reply, at most.
+pub const MEMORY_RECALL_COUNT: usize = 3;
+/// How similar a memory must be to the message being answered to be recalled.
+pub const MEMORY_MIN_SIMILARITY: f32 = 0.35;
+/// Memories kept per channel; the oldest go first.
+pub const MEMORY_MAX_ENTRIES: usize = 5000;
+/// Knowledge-base documents are split into passages of about this many characters.
+pub const KNOWLEDGE_CHUNK_CHARS: usize = 1500;
+pub const KNOWLEDGE_MAX_DOCUMENT_BYTES: usize = 1_000_000;
+/// Passages of a document being embedded at once, at most.
+pub const KNOWLEDGE_EMBEDS_IN_FLIGHT: usize = 8;
+/// Passages consulted for each reply, at most.
+pub const KNOWLEDGE_RECALL_COUNT: usize = 4;
+pub const KNOWLEDGE_MIN_SIMILARITY: f32 = 0.3;
+/// Images sent along with the message being answered, at most.
+pub const VISION_MAX_IMAGES: usize = 4;
+pub const VISION_MAX_IMAGE_BYTES: u64 = 20_000_000;
+/// What an image costs of the context: the most OpenAI counts one at high detail,
+/// which is more than llava's 576.
+pub const VISION_IMAGE_TOKENS: usize = 1105;
+/// Messages whose downloaded images are kept for retries, at most.
+pub const VISION_CACHED_MESSAGES: usize = 8;
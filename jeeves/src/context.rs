@@ -0,0 +1,98 @@
+use crate::types::*;
+
+/// Rough token count. English runs about four characters to a token, but code,
+/// names and other scripts run denser, so we allow a token for every three bytes:
+/// a margin for English, and about one token a character for CJK text.
+pub fn estimate_tokens(text: &str) -> usize {
+    (text.len() + 2) / 3
+}
+
+/// Role markers, names and separators cost a few tokens on top of each message's text.
+pub const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+pub fn estimate_message_tokens(message: &(Speaker, String)) -> usize {
+    let name = match &message.0 {
+        Speaker::User(name) => estimate_tokens(name),
+        Speaker::System | Speaker::Jeeves => 0,
+    };
+    name + estimate_tokens(&message.1) + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// Drop the oldest turns until the conversation fits in `budget` tokens.
+/// `messages` starts with the system prompt, which is always kept, as is the
+/// newest message; between them we keep as many of the most recent as fit.
+pub fn fit_to_budget(messages: Vec<(Speaker, String)>, budget: usize) -> Vec<(Speaker, String)> {
+    let mut messages = messages.into_iter();
+    let Some(system) = messages.next() else {
+        return vec![];
+    };
+    let rest = messages.collect::<Vec<(Speaker, String)>>();
+
+    let mut used = estimate_message_tokens(&system);
+    let mut kept = vec![];
+    for (i, message) in rest.into_iter().rev().enumerate() {
+        let cost = estimate_message_tokens(&message);
+        if i > 0 && used + cost > budget {
+            break;
+        }
+        used += cost;
+        kept.push(message);
+    }
+    kept.push(system);
+    kept.reverse();
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(speaker: &str, content: &str) -> (Speaker, String) {
+        let speaker = match speaker {
+            "system" => Speaker::System,
+            name => Speaker::User(name.to_string()),
+        };
+        (speaker, content.to_string())
+    }
+
+    #[test]
+    fn estimates_leave_a_margin() {
+        assert_eq!(estimate_tokens(""), 0);
+        // "hello world" is two tokens to most tokenizers
+        assert!(estimate_tokens("hello world") >= 3);
+        // each of these characters is a token or more
+        assert!(estimate_tokens("今日は") >= 3);
+    }
+
+    #[test]
+    fn keeps_system_prompt_and_newest_turns() {
+        let messages = vec![
+            message("system", "Be helpful."),
+            message("alice", &"old ".repeat(50)),
+            message("bob", "middle"),
+            message("alice", "newest"),
+        ];
+        let budget = estimate_message_tokens(&messages[0])
+            + estimate_message_tokens(&messages[2])
+            + estimate_message_tokens(&messages[3]);
+        let kept = fit_to_budget(messages.clone(), budget);
+        assert_eq!(
+            kept,
+            vec![
+                messages[0].clone(),
+                messages[2].clone(),
+                messages[3].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_newest_turn_even_over_budget() {
+        let messages = vec![
+            message("system", "Be helpful."),
+            message("alice", &"long ".repeat(100)),
+        ];
+        assert_eq!(fit_to_budget(messages.clone(), 1), messages);
+        assert!(fit_to_budget(vec![], 100).is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::types::*;
+
+/// Append to a channel's log, dropping the oldest utterances beyond the capture
+/// window. Returns those dropped.
+pub fn push_utterance(
+    guild: &mut GuildInfo,
+    channel_id: &String,
+    utterance: Utterance,
+) -> Vec<Utterance> {
+    let window = guild.capture.window.max(1);
+    let log = guild
+        .message_log
+        .entry(channel_id.clone())
+        .or_insert(vec![]);
+    log.push(utterance);
+    if log.len() > window {
+        let excess = log.len() - window;
+        return log.drain(..excess).collect();
+    }
+    vec![]
+}
+
+pub fn has_utterance(guild: &GuildInfo, channel_id: &String, message_id: &String) -> bool {
+    guild
+        .message_log
+        .get(channel_id)
+        .unwrap_or(&vec![])
+        .iter()
+        .any(|m| m.id.as_ref() == Some(message_id))
+}
+
+pub fn is_bot_mention(content: &str, bot_user_id: &str) -> bool {
+    content.contains(&format!("<@{}>", bot_user_id))
+        || content.contains(&format!("<@!{}>", bot_user_id))
+}
+
+/// Swap `<@id>` mention tokens for readable `@username`s, so the model sees names
+/// rather than snowflakes. Mentions of us become `@` and our own username.
+pub fn humanize_mentions(
+    content: &str,
+    mentions: &[discord_api::User],
+    bot_user_id: &String,
+    bot_name: &str,
+) -> String {
+    let mut content = content.to_string();
+    let bot_mention = format!("@{}", bot_name);
+    for user in mentions {
+        let name = if &user.id == bot_user_id {
+            bot_name
+        } else {
+            user.username.as_str()
+        };
+        content = content
+            .replace(&format!("<@{}>", user.id), &format!("@{}", name))
+            .replace(&format!("<@!{}>", user.id), &format!("@{}", name));
+    }
+    content
+        .replace(&format!("<@{}>", bot_user_id), &bot_mention)
+        .replace(&format!("<@!{}>", bot_user_id), &bot_mention)
+}
+
+/// Replace the text of a logged utterance. Returns false if we never logged it.
+pub fn edit_utterance(
+    guild: &mut GuildInfo,
+    channel_id: &String,
+    message_id: &String,
+    content: String,
+) -> bool {
+    let Some(utterance) = guild
+        .message_log
+        .get_mut(channel_id)
+        .and_then(|log| log.iter_mut().find(|m| m.id.as_ref() == Some(message_id)))
+    else {
+        return false;
+    };
+    utterance.content = content;
+    true
+}
+
+pub fn remove_utterances(guild: &mut GuildInfo, channel_id: &String, message_ids: &[String]) {
+    if let Some(log) = guild.message_log.get_mut(channel_id) {
+        log.retain(|m| match &m.id {
+            Some(id) => !message_ids.contains(id),
+            None => true,
+        });
+    }
+}
+
+pub fn reply_to(guild: &GuildInfo, channel_id: &String, message_id: &String) -> Option<usize> {
+    guild
+        .message_log
+        .get(channel_id)?
+        .iter()
+        .position(|m| m.in_reply_to.as_ref() == Some(message_id))
+}
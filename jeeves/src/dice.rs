@@ -0,0 +1,126 @@
+use rand::Rng;
+
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+/// Roll dice written like `2d6+3`, `d20` or `1d8 + 2d4 - 1`.
+pub fn roll_dice(notation: &str) -> anyhow::Result<String> {
+    let notation = notation.replace(' ', "").to_lowercase();
+    if notation.is_empty() {
+        return Err(anyhow::anyhow!("no dice given"));
+    }
+    let mut rng = rand::thread_rng();
+    let mut total: i64 = 0;
+    let mut parts = vec![];
+    let mut dice_rolled = 0;
+
+    // split into signed terms: "2d6+3-d4" -> +2d6, +3, -d4
+    let mut terms = vec![];
+    let mut current = String::new();
+    for c in notation.chars() {
+        if (c == '+' || c == '-') && !current.is_empty() {
+            terms.push(current.clone());
+            current.clear();
+        }
+        current.push(c);
+    }
+    terms.push(current);
+
+    for term in terms {
+        let (sign, body) = match term.strip_prefix('-') {
+            Some(body) => (-1, body.to_string()),
+            None => (1, term.trim_start_matches('+').to_string()),
+        };
+        match body.split_once('d') {
+            Some((count, sides)) => {
+                let count = if count.is_empty() {
+                    1
+                } else {
+                    count
+                        .parse::<u32>()
+                        .map_err(|_| anyhow::anyhow!("{} is not a number of dice", count))?
+                };
+                let sides = sides
+                    .parse::<u32>()
+                    .map_err(|_| anyhow::anyhow!("{} is not a number of sides", sides))?;
+                dice_rolled = count.saturating_add(dice_rolled);
+                if count == 0 || sides == 0 || dice_rolled > MAX_DICE || sides > MAX_SIDES {
+                    return Err(anyhow::anyhow!(
+                        "I roll between 1 and {} dice of up to {} sides",
+                        MAX_DICE,
+                        MAX_SIDES
+                    ));
+                }
+                let rolls = (0..count)
+                    .map(|_| rng.gen_range(1..=sides))
+                    .collect::<Vec<u32>>();
+                let sum = rolls.iter().map(|r| *r as i64).sum::<i64>();
+                total += sign * sum;
+                parts.push(format!(
+                    "{}{}d{} [{}]",
+                    if sign < 0 { "-" } else { "" },
+                    count,
+                    sides,
+                    rolls
+                        .iter()
+                        .map(|r| r.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ));
+            }
+            None => {
+                let modifier = body
+                    .parse::<i64>()
+                    .map_err(|_| anyhow::anyhow!("{} is neither dice nor a number", body))?;
+                total = modifier
+                    .checked_mul(sign)
+                    .and_then(|m| total.checked_add(m))
+                    .ok_or_else(|| anyhow::anyhow!("{} is too large a number", body))?;
+                parts.push(format!("{}{}", if sign < 0 { "-" } else { "" }, modifier));
+            }
+        }
+    }
+    Ok(format!(
+        "{} = {}",
+        parts.join(" + ").replace("+ -", "- "),
+        total
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The total after the "=".
+    fn total(result: &str) -> i64 {
+        result.rsplit("= ").next().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn rolls_within_range() {
+        for _ in 0..100 {
+            let t = total(&roll_dice("2d6+3").unwrap());
+            assert!((5..=15).contains(&t));
+            let t = total(&roll_dice("d20 - 1").unwrap());
+            assert!((0..=19).contains(&t));
+        }
+    }
+
+    #[test]
+    fn shows_each_roll() {
+        assert_eq!(roll_dice("3d1").unwrap(), "3d1 [1, 1, 1] = 3");
+        assert_eq!(roll_dice("1d1 - 2").unwrap(), "1d1 [1] - 2 = -1");
+    }
+
+    #[test]
+    fn refuses_bad_dice() {
+        assert!(roll_dice("").is_err());
+        assert!(roll_dice("0d6").is_err());
+        assert!(roll_dice("101d6").is_err());
+        assert!(roll_dice("60d6+60d6").is_err());
+        assert!(roll_dice("4294967295d6+2d6").is_err());
+        assert!(roll_dice("d1001").is_err());
+        assert!(roll_dice("2x6").is_err());
+        assert!(roll_dice("9223372036854775807+1").is_err());
+    }
+}
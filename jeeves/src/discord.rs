@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 
+use crate::consts::*;
 use crate::empty_state;
+use crate::errors::JeevesError;
 use crate::types::*;
 use discord_api::BotId;
 use discord_api::DiscordApiRequest;
@@ -8,9 +10,12 @@ use discord_api::HttpApiCall;
 use discord_api::InteractionCallbackData;
 use discord_api::InteractionsCall;
 use discord_api::MessagesCall;
+use kinode_process_lib::http::{
+    HttpClientAction, HttpClientError, HttpClientResponse, OutgoingHttpRequest,
+};
 use kinode_process_lib::{
-    await_message, call_init, get_typed_state, println, set_state, Address, Message, ProcessId,
-    Request, SendError,
+    await_message, call_init, get_blob, get_typed_state, println, set_state, timer::set_timer,
+    Address, Message, ProcessId, Request, SendError,
 };
 
 pub fn send_message_to_discord(
@@ -22,16 +27,7 @@ pub fn send_message_to_discord(
     interaction_token: Option<String>,
 ) -> anyhow::Result<()> {
     println!("jeeves: attempting to send message to discord: {}", msg);
-    // if message is longer than 900 chars, send multiple calls
-    let chunks = if msg.len() > 900 {
-        let mut chunks = vec![];
-        for chunk in msg.as_bytes().chunks(900) {
-            chunks.push(String::from_utf8_lossy(chunk).to_string());
-        }
-        chunks
-    } else {
-        vec![msg]
-    };
+    let chunks = split_message(&msg);
     let calls = if let Some(interaction_token) = interaction_token {
         println!("jeeves: interaction token found");
         chunks
@@ -75,7 +71,199 @@ pub fn send_message_to_discord(
                 call,
             })?)
             .expects_response(5)
-            .send()?;
+            .send()
+            .map_err(|e| JeevesError::DiscordDelivery(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// If a message is longer than 900 bytes, split it for multiple calls,
+/// without cutting through a character.
+pub fn split_message(msg: &str) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for c in msg.chars() {
+        if chunk.len() + c.len_utf8() > 900 {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Post a message whose id we need back: the Discord API's response to it
+/// arrives in the Response branch carrying `context`.
+pub fn post_message_with_context(
+    msg: String,
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    channel_id: String,
+    context: &ResponseContext,
+) -> anyhow::Result<()> {
+    Request::new()
+        .target((our.node.as_ref(), discord_api_id.clone()))
+        .body(serde_json::to_vec(&DiscordApiRequest::Http {
+            bot: bot.clone(),
+            call: HttpApiCall::Messages(MessagesCall::Create {
+                channel_id,
+                content: msg,
+            }),
+        })?)
+        .context(serde_json::to_vec(context)?)
+        .expects_response(5)
+        .send()
+        .map_err(|e| JeevesError::DiscordDelivery(e.to_string()).into())
+}
+
+pub fn edit_discord_message(
+    msg: String,
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    channel_id: String,
+    message_id: String,
+) -> anyhow::Result<()> {
+    Request::new()
+        .target((our.node.as_ref(), discord_api_id.clone()))
+        .body(serde_json::to_vec(&DiscordApiRequest::Http {
+            bot: bot.clone(),
+            call: HttpApiCall::Messages(MessagesCall::Edit {
+                channel_id,
+                message_id,
+                content: msg,
+            }),
+        })?)
+        .expects_response(5)
+        .send()
+        .map_err(|e| JeevesError::DiscordDelivery(e.to_string()).into())
+}
+
+/// Post a finished reply to a channel. Each part of it is posted with its text
+/// in the context, so that if Discord doesn't take it, it can be posted again
+/// without going back to the model.
+pub fn deliver_reply(
+    msg: String,
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    channel_id: String,
+) -> anyhow::Result<()> {
+    for content in split_message(&msg) {
+        post_delivery(
+            our,
+            bot,
+            discord_api_id,
+            ReplyDelivery {
+                channel_id: channel_id.clone(),
+                content,
+                attempt: 0,
+            },
+        )?;
     }
     Ok(())
 }
+
+pub fn post_delivery(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    delivery: ReplyDelivery,
+) -> anyhow::Result<()> {
+    if let Err(e) = post_message_with_context(
+        delivery.content.clone(),
+        our,
+        bot,
+        discord_api_id,
+        delivery.channel_id.clone(),
+        &ResponseContext::Delivery(delivery.clone()),
+    ) {
+        println!("jeeves: {}", e);
+        return handle_delivery_failure(delivery);
+    }
+    Ok(())
+}
+
+/// Discord sends back the message it created; anything else means the post
+/// didn't take.
+pub fn handle_delivery_response(delivery: ReplyDelivery, body: &[u8]) -> anyhow::Result<()> {
+    if created_message_id(body).is_some() {
+        return Ok(());
+    }
+    println!(
+        "jeeves: Discord did not take a reply: {}",
+        String::from_utf8_lossy(&body[..body.len().min(300)])
+    );
+    handle_delivery_failure(delivery)
+}
+
+/// Wait a little longer each time and post the same text again, until we have
+/// tried `DISCORD_MAX_RETRIES` times.
+pub fn handle_delivery_failure(delivery: ReplyDelivery) -> anyhow::Result<()> {
+    if delivery.attempt >= DISCORD_MAX_RETRIES {
+        println!(
+            "jeeves: gave up posting a reply to {}: {}",
+            delivery.channel_id, delivery.content
+        );
+        return Ok(());
+    }
+    set_timer(
+        DISCORD_RETRY_BACKOFF_MS * 2u64.pow(delivery.attempt),
+        Some(serde_json::to_vec(&ResponseContext::RetryDelivery(
+            ReplyDelivery {
+                attempt: delivery.attempt + 1,
+                ..delivery
+            },
+        ))?),
+    );
+    Ok(())
+}
+
+/// Dig the id of a message we created out of the Discord API's response.
+pub fn created_message_id(body: &[u8]) -> Option<String> {
+    fn find_id(value: &serde_json::Value) -> Option<String> {
+        let object = value.as_object()?;
+        if let Some(id) = object.get("id").and_then(|id| id.as_str()) {
+            return Some(id.to_string());
+        }
+        // the message may be wrapped in a response enum or two
+        object.values().find_map(find_id)
+    }
+    find_id(&serde_json::from_slice::<serde_json::Value>(body).ok()?)
+}
+
+/// Fetch a file, such as an attachment, without waiting on it: http_client's
+/// response arrives in the Response branch carrying `context`.
+pub fn request_download(url: &str, context: &ResponseContext) -> anyhow::Result<()> {
+    Request::new()
+        .target(("our", "http_client", "distro", "sys"))
+        .body(serde_json::to_vec(&HttpClientAction::Http(
+            OutgoingHttpRequest {
+                method: "GET".to_string(),
+                version: None,
+                url: url.to_string(),
+                headers: HashMap::new(),
+            },
+        ))?)
+        .context(serde_json::to_vec(context)?)
+        .expects_response(LLM_TIMEOUT_SECS)
+        .send()
+}
+
+/// The file http_client fetched for `request_download`.
+pub fn downloaded_bytes(body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match serde_json::from_slice::<Result<HttpClientResponse, HttpClientError>>(body)? {
+        Ok(HttpClientResponse::Http(response)) if response.status == 200 => {}
+        Ok(HttpClientResponse::Http(response)) => {
+            return Err(anyhow::anyhow!("the server answered {}", response.status))
+        }
+        Ok(response) => return Err(anyhow::anyhow!("unexpected response {:?}", response)),
+        Err(e) => return Err(anyhow::anyhow!("{:?}", e)),
+    }
+    get_blob()
+        .map(|blob| blob.bytes)
+        .ok_or(anyhow::anyhow!("the response came without the file"))
+}
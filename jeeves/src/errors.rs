@@ -0,0 +1,196 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::consts::*;
+use kinode_process_lib::SendErrorKind;
+
+/// What went wrong in getting a reply from a model or to Discord, sorted by what
+/// can be done about it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum JeevesError {
+    Timeout,
+    RateLimited,
+    /// The provider won't take our credentials.
+    Auth,
+    /// The conversation is too long for the model.
+    ContextOverflow,
+    /// The provider declined to answer.
+    ContentFilter,
+    /// The provider answered with something we can't read.
+    Malformed(String),
+    DiscordDelivery(String),
+    Other(String),
+}
+
+/// What to do about a failed request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RetryPolicy {
+    /// Try again after this many milliseconds, up to `LLM_MAX_RETRIES` times.
+    Backoff(u64),
+    /// Try the same model again with less of the conversation.
+    ShrinkContext,
+    /// Go on to the next model in the chain.
+    NextModel,
+    /// Apologize to the channel.
+    GiveUp,
+}
+
+impl fmt::Display for JeevesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JeevesError::Timeout => write!(f, "timed out"),
+            JeevesError::RateLimited => write!(f, "rate limited"),
+            JeevesError::Auth => write!(f, "authentication failed"),
+            JeevesError::ContextOverflow => write!(f, "context length exceeded"),
+            JeevesError::ContentFilter => write!(f, "stopped by a content filter"),
+            JeevesError::Malformed(detail) => write!(f, "malformed response: {}", detail),
+            JeevesError::DiscordDelivery(detail) => {
+                write!(f, "Discord delivery failed: {}", detail)
+            }
+            JeevesError::Other(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+impl std::error::Error for JeevesError {}
+
+impl JeevesError {
+    /// Sort an error by the code, type or HTTP status the provider gave it, e.g.
+    /// OpenAI's `{"error": {"code": "context_length_exceeded", ...}}`. Anything
+    /// we don't recognize is left to the caller.
+    pub fn classify(body: &[u8]) -> Option<JeevesError> {
+        let value = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+        let mut fields = vec![];
+        for object in [Some(&value), value.get("error")].into_iter().flatten() {
+            for key in ["code", "type", "status", "status_code"] {
+                match object.get(key) {
+                    Some(serde_json::Value::String(s)) => fields.push(s.to_lowercase()),
+                    Some(serde_json::Value::Number(n)) => fields.push(n.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        fields.iter().find_map(|field| match field.as_str() {
+            "408" | "504" | "timeout" | "request_timeout" => Some(JeevesError::Timeout),
+            "429"
+            | "503"
+            | "529"
+            | "rate_limit_exceeded"
+            | "rate_limit_error"
+            | "overloaded_error"
+            | "unavailable_error" => Some(JeevesError::RateLimited),
+            "401" | "403" | "invalid_api_key" | "authentication_error" | "permission_error" => {
+                Some(JeevesError::Auth)
+            }
+            "413" | "context_length_exceeded" | "exceed_context_size_error" => {
+                Some(JeevesError::ContextOverflow)
+            }
+            "content_filter" | "content_policy_violation" => Some(JeevesError::ContentFilter),
+            _ => None,
+        })
+    }
+
+    pub fn from_anyhow(error: &anyhow::Error) -> JeevesError {
+        if let Some(error) = error.downcast_ref::<JeevesError>() {
+            return error.clone();
+        }
+        if let Some(error) = error.downcast_ref::<serde_json::Error>() {
+            return JeevesError::Malformed(error.to_string());
+        }
+        JeevesError::Other(error.to_string())
+    }
+
+    /// An unreadable response usually says why in its body, so look there first.
+    pub fn from_response(error: &anyhow::Error, body: &[u8]) -> JeevesError {
+        JeevesError::classify(body).unwrap_or_else(|| JeevesError::from_anyhow(error))
+    }
+
+    pub fn from_send_error(kind: &SendErrorKind) -> JeevesError {
+        match kind {
+            SendErrorKind::Timeout => JeevesError::Timeout,
+            SendErrorKind::Offline => JeevesError::Other("the llm process is offline".to_string()),
+        }
+    }
+
+    /// `attempt` is how many retries have already been made.
+    pub fn retry_policy(&self, attempt: u32) -> RetryPolicy {
+        let backoff = LLM_RETRY_BACKOFF_MS * 2u64.pow(attempt);
+        match self {
+            JeevesError::Timeout => RetryPolicy::Backoff(backoff),
+            // the provider has told us to slow down, so do
+            JeevesError::RateLimited => RetryPolicy::Backoff(backoff * 4),
+            JeevesError::ContextOverflow => RetryPolicy::ShrinkContext,
+            // no other model will think better of it
+            JeevesError::ContentFilter => RetryPolicy::GiveUp,
+            // asking a model again won't help Discord; finished replies are
+            // retried on their own by `deliver_reply`
+            JeevesError::DiscordDelivery(_) => RetryPolicy::GiveUp,
+            JeevesError::Auth | JeevesError::Malformed(_) | JeevesError::Other(_) => {
+                RetryPolicy::NextModel
+            }
+        }
+    }
+
+    /// What to tell the channel when we can do no more.
+    pub fn user_message(&self) -> String {
+        match self {
+            JeevesError::Timeout => "I regret, sir, that my enquiries went unanswered for rather too long. Might I trouble you to ask again presently?",
+            JeevesError::RateLimited => "I fear I am being made to wait my turn, sir. If you would be so good as to ask again in a moment.",
+            JeevesError::Auth => "I regret, sir, that I have been refused admittance by my usual sources. My credentials appear to be amiss, and the master of the house may wish to look into it.",
+            JeevesError::ContextOverflow => "I fear our conversation has grown too long for me to hold in mind at once, sir. Perhaps `/clear` would be of assistance.",
+            JeevesError::ContentFilter => "I'm afraid that is a matter on which I must decline to comment, sir.",
+            JeevesError::Malformed(_) => "I received a most garbled answer from my sources, sir, and could make nothing of it.",
+            JeevesError::DiscordDelivery(_) => "I regret, sir, that a message of mine went astray.",
+            JeevesError::Other(_) => "Something has gone amiss, sir, and I am unable to oblige at present.",
+        }
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_openai_error_codes() {
+        let body = br#"{"error":{"message":"This model's maximum context length is 4097 tokens","type":"invalid_request_error","code":"context_length_exceeded"}}"#;
+        assert_eq!(
+            JeevesError::classify(body),
+            Some(JeevesError::ContextOverflow)
+        );
+        let body = br#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","code":"invalid_api_key"}}"#;
+        assert_eq!(JeevesError::classify(body), Some(JeevesError::Auth));
+    }
+
+    #[test]
+    fn classifies_statuses() {
+        assert_eq!(
+            JeevesError::classify(br#"{"status": 429}"#),
+            Some(JeevesError::RateLimited)
+        );
+        assert_eq!(
+            JeevesError::classify(br#"{"error":{"code":500,"type":"exceed_context_size_error"}}"#),
+            Some(JeevesError::ContextOverflow)
+        );
+    }
+
+    #[test]
+    fn numbers_in_messages_are_not_statuses() {
+        let body = br#"{"error":{"message":"expected 401 tokens, got 429","type":"server_error"}}"#;
+        assert_eq!(JeevesError::classify(body), None);
+        assert_eq!(JeevesError::classify(b"429 Too Many Requests"), None);
+    }
+
+    #[test]
+    fn discord_failures_do_not_retry_the_model() {
+        assert_eq!(
+            JeevesError::DiscordDelivery("timed out".to_string()).retry_policy(0),
+            RetryPolicy::GiveUp
+        );
+        assert_eq!(
+            JeevesError::Timeout.retry_policy(1),
+            RetryPolicy::Backoff(LLM_RETRY_BACKOFF_MS * 2)
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use crate::completion::*;
+use crate::consts::*;
+use crate::models::*;
+use crate::providers::*;
+use crate::types::*;
+
+pub const GEN_PARAM_NAMES: [&str; 8] = [
+    "max_tokens",
+    "temperature",
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "stop",
+    "seed",
+    "logit_bias",
+];
+
+pub fn default_gen_params() -> GenerationParams {
+    GenerationParams {
+        max_tokens: Some(MAX_REPLY_TOKENS as u32),
+        temperature: Some(1.25),
+        ..Default::default()
+    }
+}
+
+/// Fill whatever `params` leaves unset from `base`.
+fn overlay(params: &GenerationParams, base: &GenerationParams) -> GenerationParams {
+    GenerationParams {
+        max_tokens: params.max_tokens.or(base.max_tokens),
+        temperature: params.temperature.or(base.temperature),
+        top_p: params.top_p.or(base.top_p),
+        presence_penalty: params.presence_penalty.or(base.presence_penalty),
+        frequency_penalty: params.frequency_penalty.or(base.frequency_penalty),
+        stop: params.stop.clone().or(base.stop.clone()),
+        seed: params.seed.or(base.seed),
+        logit_bias: params.logit_bias.clone().or(base.logit_bias.clone()),
+    }
+}
+
+/// The channel's overrides, then the guild's settings, then the defaults.
+pub fn effective_gen_params(guild: &GuildInfo, channel_id: &String) -> GenerationParams {
+    let guild_params = overlay(&guild.gen_params, &default_gen_params());
+    match guild.channel_configs.get(channel_id) {
+        Some(channel) => overlay(&channel.gen_params, &guild_params),
+        None => guild_params,
+    }
+}
+
+/// Reply room reserved in the context window.
+pub fn reply_tokens(params: &GenerationParams) -> usize {
+    params.max_tokens.unwrap_or(MAX_REPLY_TOKENS as u32) as usize
+}
+
+/// Set one parameter from its slash command text, or clear it if `value` is None.
+pub fn set_gen_param(
+    params: &mut GenerationParams,
+    name: &str,
+    value: Option<&str>,
+) -> Result<(), String> {
+    let Some(value) = value.map(|v| v.trim()) else {
+        match name {
+            "max_tokens" => params.max_tokens = None,
+            "temperature" => params.temperature = None,
+            "top_p" => params.top_p = None,
+            "presence_penalty" => params.presence_penalty = None,
+            "frequency_penalty" => params.frequency_penalty = None,
+            "stop" => params.stop = None,
+            "seed" => params.seed = None,
+            "logit_bias" => params.logit_bias = None,
+            _ => return Err(format!("Unknown setting: {}", name)),
+        }
+        return Ok(());
+    };
+    let float = |v: &str| {
+        v.parse::<f64>()
+            .map_err(|_| format!("{} must be a number, not {}", name, v))
+    };
+    match name {
+        "max_tokens" => {
+            params.max_tokens = Some(
+                value
+                    .parse::<u32>()
+                    .map_err(|_| format!("max_tokens must be a whole number, not {}", value))?,
+            )
+        }
+        "temperature" => params.temperature = Some(float(value)?),
+        "top_p" => params.top_p = Some(float(value)?),
+        "presence_penalty" => params.presence_penalty = Some(float(value)?),
+        "frequency_penalty" => params.frequency_penalty = Some(float(value)?),
+        "stop" => {
+            params.stop = Some(
+                value
+                    .split('|')
+                    .map(|s| s.to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+            )
+        }
+        "seed" => {
+            params.seed = Some(
+                value
+                    .parse::<i64>()
+                    .map_err(|_| format!("seed must be a whole number, not {}", value))?,
+            )
+        }
+        "logit_bias" => {
+            let mut bias = HashMap::new();
+            for pair in value.split(',').filter(|p| !p.trim().is_empty()) {
+                let Some((token, amount)) = pair.split_once(':') else {
+                    return Err(format!(
+                        "logit_bias takes token:bias pairs separated by commas, not {}",
+                        pair
+                    ));
+                };
+                let token = token.trim();
+                if token.parse::<i32>().is_err() {
+                    return Err(format!("{} is not a token ID", token));
+                }
+                let amount = amount
+                    .trim()
+                    .parse::<i32>()
+                    .map_err(|_| format!("{} is not a whole-number bias", amount.trim()))?;
+                bias.insert(token.to_string(), amount);
+            }
+            params.logit_bias = Some(bias);
+        }
+        _ => return Err(format!("Unknown setting: {}", name)),
+    }
+    Ok(())
+}
+
+/// Check `params` against what `provider` accepts.
+pub fn validate_gen_params(provider: &Provider, params: &GenerationParams) -> Result<(), String> {
+    let in_range = |name: &str, value: Option<f64>, min: f64, max: f64| match value {
+        Some(v) if v < min || v > max => Err(format!(
+            "{} must be between {} and {} for {:?}, not {}",
+            name, min, max, provider, v
+        )),
+        _ => Ok(()),
+    };
+    let max_temperature = match provider {
+        Provider::OpenAi => 2.0,
+        Provider::Lccp => 5.0,
+    };
+    in_range(
+        "max_tokens",
+        params.max_tokens.map(|t| t as f64),
+        1.0,
+        4096.0,
+    )?;
+    in_range("temperature", params.temperature, 0.0, max_temperature)?;
+    in_range("top_p", params.top_p, 0.0, 1.0)?;
+    in_range("presence_penalty", params.presence_penalty, -2.0, 2.0)?;
+    in_range("frequency_penalty", params.frequency_penalty, -2.0, 2.0)?;
+    if let Some(stop) = &params.stop {
+        if provider == &Provider::OpenAi && stop.len() > 4 {
+            return Err("OpenAi takes at most 4 stop sequences".to_string());
+        }
+    }
+    if let Some(bias) = &params.logit_bias {
+        for amount in bias.values() {
+            in_range("each logit_bias", Some(*amount as f64), -100.0, 100.0)?;
+        }
+    }
+    if provider == &Provider::OpenAi {
+        in_range(
+            "seed",
+            params.seed.map(|s| s as f64),
+            i32::MIN as f64,
+            i32::MAX as f64,
+        )?;
+    }
+    Ok(())
+}
+
+/// Check the settings each channel would write with against every model that
+/// may answer there. Run it on a guild with a change applied, before keeping it.
+pub fn validate_guild_gen_params(
+    models: &HashMap<String, ModelInfo>,
+    guild: &GuildInfo,
+) -> Result<(), String> {
+    // the empty channel stands for any channel without settings of its own
+    let mut channels = vec![String::new()];
+    channels.extend(guild.our_channels.iter().cloned());
+    channels.extend(guild.channel_configs.keys().cloned());
+    for channel_id in &channels {
+        let params = effective_gen_params(guild, channel_id);
+        for model in model_chain(guild, channel_id) {
+            validate_gen_params(&provider_for_model(models, &model), &params)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn describe_gen_params(params: &GenerationParams) -> String {
+    let fmt = |v: Option<String>| v.unwrap_or("(default)".to_string());
+    format!(
+        "max_tokens {}, temperature {}, top_p {}, presence_penalty {}, frequency_penalty {}, stop {}, seed {}, logit_bias {}",
+        fmt(params.max_tokens.map(|v| v.to_string())),
+        fmt(params.temperature.map(|v| v.to_string())),
+        fmt(params.top_p.map(|v| v.to_string())),
+        fmt(params.presence_penalty.map(|v| v.to_string())),
+        fmt(params.frequency_penalty.map(|v| v.to_string())),
+        fmt(params.stop.as_ref().map(|v| format!("{:?}", v))),
+        fmt(params.seed.map(|v| v.to_string())),
+        fmt(params.logit_bias.as_ref().map(|v| {
+            let mut pairs = v
+                .iter()
+                .map(|(token, bias)| format!("{}:{}", token, bias))
+                .collect::<Vec<String>>();
+            pairs.sort();
+            pairs.join(",")
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openai_seeds_must_fit_in_an_i32() {
+        let mut params = GenerationParams::default();
+        set_gen_param(&mut params, "seed", Some("4294967296")).unwrap();
+        assert!(validate_gen_params(&Provider::OpenAi, &params).is_err());
+        assert!(validate_gen_params(&Provider::Lccp, &params).is_ok());
+        set_gen_param(&mut params, "seed", Some("-42")).unwrap();
+        assert!(validate_gen_params(&Provider::OpenAi, &params).is_ok());
+    }
+
+    #[test]
+    fn temperature_limits_depend_on_provider() {
+        let mut params = GenerationParams::default();
+        set_gen_param(&mut params, "temperature", Some("3")).unwrap();
+        assert!(validate_gen_params(&Provider::OpenAi, &params).is_err());
+        assert!(validate_gen_params(&Provider::Lccp, &params).is_ok());
+        assert!(set_gen_param(&mut params, "temperature", Some("warm")).is_err());
+    }
+}
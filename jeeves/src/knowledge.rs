@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::consts::*;
+use crate::empty_state;
+use crate::memory::*;
+use crate::providers::*;
+use crate::types::*;
+use crate::usage::*;
+use kinode_process_lib::{get_typed_state, println, vfs, Address, Request};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnowledgeChunk {
+    pub document: String,
+    pub text: String,
+    pub embedder: String,
+    pub embedding: Vec<f32>,
+}
+
+/// A guild's documents, as embedded passages, stored as one bincode file on our VFS drive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KnowledgeBase {
+    pub chunks: Vec<KnowledgeChunk>,
+    /// The current upload of each document, by name.
+    pub uploads: HashMap<String, u64>,
+}
+
+/// The knowledge bases of the guilds we have looked at, by guild ID, so that
+/// replies don't read them from disk.
+fn knowledge() -> MutexGuard<'static, HashMap<String, KnowledgeBase>> {
+    static KNOWLEDGE: OnceLock<Mutex<HashMap<String, KnowledgeBase>>> = OnceLock::new();
+    KNOWLEDGE
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+fn knowledge_drive() -> Option<String> {
+    get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state())
+        .knowledge_drive
+}
+
+/// The guild's knowledge base, read from disk if it isn't in `cache` yet.
+/// Bases used to be saved as JSON, which is read if there is nothing newer.
+fn guild_knowledge<'a>(
+    cache: &'a mut HashMap<String, KnowledgeBase>,
+    guild_id: &String,
+) -> &'a mut KnowledgeBase {
+    cache.entry(guild_id.clone()).or_insert_with(|| {
+        let Some(drive) = knowledge_drive() else {
+            return KnowledgeBase::default();
+        };
+        let read = |path: String| {
+            vfs::open_file(&path, false)
+                .and_then(|file| file.read())
+                .ok()
+                .filter(|bytes| !bytes.is_empty())
+        };
+        if let Some(bytes) = read(format!("{}/{}.bin", drive, guild_id)) {
+            return bincode::deserialize::<KnowledgeBase>(&bytes).unwrap_or_default();
+        }
+        read(format!("{}/{}.json", drive, guild_id))
+            .and_then(|bytes| serde_json::from_slice::<KnowledgeBase>(&bytes).ok())
+            .unwrap_or_default()
+    })
+}
+
+fn save_knowledge(guild_id: &String, base: &KnowledgeBase) -> anyhow::Result<()> {
+    let Some(drive) = knowledge_drive() else {
+        return Err(anyhow::anyhow!("no knowledge drive"));
+    };
+    vfs::open_file(&format!("{}/{}.bin", drive, guild_id), true)?
+        .write(&bincode::serialize(base)?)?;
+    let legacy = format!("{}/{}.json", drive, guild_id);
+    if vfs::open_file(&legacy, false).is_ok() {
+        vfs::remove_file(&legacy)?;
+    }
+    Ok(())
+}
+
+/// A document being added: its passages still to be embedded, and those done.
+/// They replace the document's old passages together, once the last is back.
+struct Upload {
+    guild_id: String,
+    document: String,
+    waiting: Vec<KnowledgePassage>,
+    in_flight: usize,
+    embedded: Vec<KnowledgeChunk>,
+    failed: usize,
+    /// Set when the document is added again or removed before this upload is
+    /// filed, which then leaves the knowledge base alone.
+    superseded: bool,
+}
+
+fn uploads() -> MutexGuard<'static, HashMap<u64, Upload>> {
+    static UPLOADS: OnceLock<Mutex<HashMap<u64, Upload>>> = OnceLock::new();
+    UPLOADS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Send the upload's next passages to be embedded, keeping a few in flight.
+fn embed_waiting(upload: &mut Upload) -> anyhow::Result<()> {
+    while upload.in_flight < KNOWLEDGE_EMBEDS_IN_FLIGHT {
+        let Some(passage) = upload.waiting.pop() else {
+            return Ok(());
+        };
+        Request::new()
+            .target(Address::new("our", provider_process(&passage.provider)))
+            .body(build_embedding_request(&passage.provider, &passage.text))
+            .context(serde_json::to_vec(&ResponseContext::KnowledgeEmbedding(
+                passage,
+            ))?)
+            .expects_response(LLM_TIMEOUT_SECS)
+            .send()?;
+        upload.in_flight += 1;
+    }
+    Ok(())
+}
+
+/// Split a document into passages at paragraph breaks, each at most about
+/// `KNOWLEDGE_CHUNK_CHARS` long. Passages under a markdown heading start with it,
+/// so they make sense on their own.
+pub fn chunk_document(text: &str) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    let mut heading = String::new();
+    for paragraph in text
+        .split("\n\n")
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+    {
+        if paragraph.starts_with('#') {
+            heading = paragraph.lines().next().unwrap_or("").to_string();
+        }
+        if !current.is_empty()
+            && current.chars().count() + paragraph.chars().count() > KNOWLEDGE_CHUNK_CHARS
+        {
+            chunks.push(current.clone());
+            current.clear();
+        }
+        if current.is_empty() && !heading.is_empty() && !paragraph.starts_with('#') {
+            current = format!("{}\n\n", heading);
+        }
+        // a paragraph too long for one passage is cut where it must be
+        let chars = paragraph.chars().collect::<Vec<char>>();
+        for piece in chars.chunks(KNOWLEDGE_CHUNK_CHARS) {
+            if !current.is_empty() && current.chars().count() + piece.len() > KNOWLEDGE_CHUNK_CHARS
+            {
+                chunks.push(current.clone());
+                current.clear();
+            }
+            current.push_str(&piece.iter().collect::<String>());
+        }
+        current.push_str("\n\n");
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    chunks.into_iter().map(|c| c.trim().to_string()).collect()
+}
+
+/// Uploads of the document still being embedded are not to be filed.
+fn supersede_uploads(guild_id: &String, name: &str) {
+    for upload in uploads().values_mut() {
+        if &upload.guild_id == guild_id && upload.document == name {
+            upload.superseded = true;
+        }
+    }
+}
+
+/// Have `text` embedded, to replace any document of the same name once its
+/// passages are in. Returns how many passages there are.
+pub fn add_document(
+    models: &HashMap<String, ModelInfo>,
+    guild: &GuildInfo,
+    name: &str,
+    text: &str,
+) -> anyhow::Result<usize> {
+    let chunks = chunk_document(text);
+    if chunks.is_empty() {
+        return Err(anyhow::anyhow!("the file has no text in it"));
+    }
+    supersede_uploads(&guild.id, name);
+
+    let upload_id = rand::random::<u64>();
+    let provider = memory_provider(models, guild);
+    let mut upload = Upload {
+        guild_id: guild.id.clone(),
+        document: name.to_string(),
+        // taken from the end, so reversed to embed them in order
+        waiting: chunks
+            .iter()
+            .rev()
+            .map(|chunk| KnowledgePassage {
+                guild_id: guild.id.clone(),
+                document: name.to_string(),
+                upload_id,
+                text: chunk.clone(),
+                provider: provider.clone(),
+            })
+            .collect(),
+        in_flight: 0,
+        embedded: vec![],
+        failed: 0,
+        superseded: false,
+    };
+    embed_waiting(&mut upload)?;
+    if upload.in_flight > 0 {
+        uploads().insert(upload_id, upload);
+    }
+    println!(
+        "jeeves: adding {} ({} passages) to the knowledge of guild {}",
+        name,
+        chunks.len(),
+        guild.id
+    );
+    Ok(chunks.len())
+}
+
+/// A passage's embedding is back, or `None` if it never will be. Once the
+/// upload's last passage is in, file them all, and charge the guild for them.
+pub fn handle_knowledge_embedding(
+    passage: KnowledgePassage,
+    body: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    let mut uploads = uploads();
+    let Some(upload) = uploads.get_mut(&passage.upload_id) else {
+        return Ok(());
+    };
+    upload.in_flight = upload.in_flight.saturating_sub(1);
+    match body.map(|b| parse_embedding_response(&passage.provider, b)) {
+        Some(Ok(embedding)) => upload.embedded.push(KnowledgeChunk {
+            document: passage.document.clone(),
+            text: passage.text.clone(),
+            embedder: embedder_name(&passage.provider),
+            embedding,
+        }),
+        Some(Err(e)) => {
+            println!(
+                "jeeves: could not embed a passage of {}: {}",
+                passage.document, e
+            );
+            upload.failed += 1;
+        }
+        None => upload.failed += 1,
+    }
+    embed_waiting(upload)?;
+    if upload.in_flight > 0 || !upload.waiting.is_empty() {
+        return Ok(());
+    }
+
+    let upload = uploads.remove(&passage.upload_id).unwrap();
+    drop(uploads);
+    if upload.failed > 0 {
+        println!(
+            "jeeves: {} passages of {} could not be embedded",
+            upload.failed, passage.document
+        );
+    }
+    let texts = upload
+        .embedded
+        .iter()
+        .map(|c| c.text.as_str())
+        .collect::<Vec<&str>>();
+    record_embedding_usage(&passage.guild_id, None, None, &passage.provider, &texts);
+
+    if upload.superseded {
+        println!(
+            "jeeves: {} was replaced or removed while it was being embedded",
+            passage.document
+        );
+        return Ok(());
+    }
+    if upload.embedded.is_empty() {
+        // keep whatever we had of the document rather than nothing
+        println!(
+            "jeeves: none of {} could be embedded; keeping the old one, if any",
+            passage.document
+        );
+        return Ok(());
+    }
+    let mut cache = knowledge();
+    let base = guild_knowledge(&mut cache, &passage.guild_id);
+    base.chunks.retain(|c| c.document != passage.document);
+    base.chunks.extend(upload.embedded);
+    base.uploads
+        .insert(passage.document.clone(), passage.upload_id);
+    save_knowledge(&passage.guild_id, base)
+}
+
+/// Returns whether there was such a document.
+pub fn remove_document(guild_id: &String, name: &str) -> anyhow::Result<bool> {
+    supersede_uploads(guild_id, name);
+    let mut cache = knowledge();
+    let base = guild_knowledge(&mut cache, guild_id);
+    if base.uploads.remove(name).is_none() {
+        return Ok(false);
+    }
+    base.chunks.retain(|c| c.document != name);
+    save_knowledge(guild_id, base)?;
+    Ok(true)
+}
+
+pub fn describe_knowledge(guild_id: &String) -> String {
+    let mut cache = knowledge();
+    let base = guild_knowledge(&mut cache, guild_id);
+    if base.uploads.is_empty() {
+        return "(no documents)".to_string();
+    }
+    let mut names = base.uploads.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    names
+        .iter()
+        .map(|name| {
+            format!(
+                "`{}` ({} passages)",
+                name,
+                base.chunks.iter().filter(|c| &c.document == name).count()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn has_knowledge(guild_id: &String, provider: &Provider) -> bool {
+    let embedder = embedder_name(provider);
+    guild_knowledge(&mut knowledge(), guild_id)
+        .chunks
+        .iter()
+        .any(|c| c.embedder == embedder)
+}
+
+/// The passages of the guild's documents most like `query`.
+pub fn consult_knowledge(guild_id: &String, provider: &Provider, query: &[f32]) -> Vec<Reference> {
+    let embedder = embedder_name(provider);
+    let mut cache = knowledge();
+    let mut scored = guild_knowledge(&mut cache, guild_id)
+        .chunks
+        .iter()
+        .filter(|c| c.embedder == embedder)
+        .map(|c| (cosine_similarity(&c.embedding, query), c))
+        .filter(|(score, _)| *score >= KNOWLEDGE_MIN_SIMILARITY)
+        .collect::<Vec<(f32, &KnowledgeChunk)>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+        .into_iter()
+        .take(KNOWLEDGE_RECALL_COUNT)
+        .map(|(_, c)| Reference {
+            document: c.document.clone(),
+            text: c.text.clone(),
+        })
+        .collect()
+}
+
+/// Reference material for the system prompt, with instructions to cite it.
+pub fn describe_references(references: &[Reference]) -> String {
+    format!(
+        "Passages from this server's documents that may help. Answer from them where they apply, and cite the document each fact came from by name, like (per rules.md):\n{}",
+        references
+            .iter()
+            .map(|r| format!("[{}]\n{}", r.document, r.text))
+            .collect::<Vec<String>>()
+            .join("\n---\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gathers_paragraphs_under_their_heading() {
+        let text = "# Rules\n\nNo spam.\n\nBe kind.\n\n# Roles\n\nMods keep order.";
+        assert_eq!(
+            chunk_document(text),
+            vec!["# Rules\n\nNo spam.\n\nBe kind.\n\n# Roles\n\nMods keep order."]
+        );
+    }
+
+    #[test]
+    fn keeps_passages_short_and_headed() {
+        let paragraph = "word ".repeat(KNOWLEDGE_CHUNK_CHARS / 10);
+        let text = format!(
+            "# Manual\n\n{}\n\n{}\n\n{}",
+            paragraph, paragraph, paragraph
+        );
+        let chunks = chunk_document(&text);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= KNOWLEDGE_CHUNK_CHARS + "# Manual\n\n".len());
+        }
+        assert!(chunks[1].starts_with("# Manual\n\n"));
+    }
+
+    #[test]
+    fn cuts_overlong_paragraphs() {
+        let text = "x".repeat(KNOWLEDGE_CHUNK_CHARS * 2 + 10);
+        let chunks = chunk_document(&text);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), text);
+        assert!(chunk_document("\n\n  \n\n").is_empty());
+    }
+}
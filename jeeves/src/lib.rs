@@ -1,16 +1,13 @@
 mod llm_types;
 use kinode::process::standard::print_to_terminal;
 use kinode_process_lib::get_blob;
+use kinode_process_lib::http::bind_http_path;
 use kinode_process_lib::http::bind_ws_path;
 use kinode_process_lib::http::send_response;
 use kinode_process_lib::http::serve_ui;
 use kinode_process_lib::http::HttpServerRequest;
 use kinode_process_lib::http::StatusCode;
-use llm_types::openai::ChatParams;
-use llm_types::openai::ChatRequest;
-use llm_types::openai::LLMRequest;
-use llm_types::openai::LLMResponse;
-use llm_types::openai::Message as OpenaiMessage;
+use kinode_process_lib::vfs;
 
 use discord_api::{
     ApplicationCommandOption, ApplicationCommandOptionType, ApplicationCommandType, BotId,
@@ -23,14 +20,55 @@ use kinode_process_lib::{
 };
 use std::collections::HashMap;
 
+mod access;
+mod calculator;
+mod clock;
 mod commands;
+mod completion;
 mod consts;
+mod context;
+mod conversation;
+mod dice;
 mod discord;
+mod errors;
+mod genparams;
+mod knowledge;
+mod memory;
+mod migrations;
+mod models;
+mod providers;
+mod quotas;
+mod ratelimit;
+mod speakers;
+mod streaming;
+mod summary;
+mod templates;
+mod tools;
+mod triggers;
 mod types;
+mod units;
+mod usage;
+mod vision;
+use crate::access::*;
 use crate::commands::*;
+use crate::completion::*;
 use crate::consts::*;
+use crate::conversation::*;
 use crate::discord::*;
+use crate::errors::*;
+use crate::knowledge::*;
+use crate::memory::*;
+use crate::migrations::*;
+use crate::models::*;
+use crate::providers::*;
+use crate::quotas::*;
+use crate::ratelimit::*;
+use crate::streaming::*;
+use crate::summary::*;
+use crate::tools::*;
+use crate::triggers::*;
 use crate::types::*;
+use crate::vision::*;
 
 wit_bindgen::generate!({
     path: "wit",
@@ -49,6 +87,12 @@ fn init(our: Address) {
     // // Bind HTTP path /messages
     // bind_http_path("/messages", true, false).unwrap();
 
+    // Bind HTTP path /knowledge, where the UI uploads documents
+    bind_http_path("/knowledge", true, false).unwrap();
+
+    // Bind HTTP path /models, where the node's owner edits the model registry
+    bind_http_path("/models", true, false).unwrap();
+
     // Bind WebSocket path
     bind_ws_path("/", true, true).unwrap();
 
@@ -142,35 +186,557 @@ fn init(our: Address) {
         },
     });
 
-    let model_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+    let prompt_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "prompt".to_string(),
+            description: Some("Show or change the instructions Jeeves follows".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "prompt".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description:
+                        "The system prompt; default restores Jeeves, inherit takes the guild's"
+                            .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "scope".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Apply to this `channel` only, or the whole `guild` (default)"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let models_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "models".to_string(),
+            description: Some("List the models Jeeves may use".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: None,
+        },
+    });
+
+    let respond_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "respond".to_string(),
+            description: Some("Choose when Jeeves replies to messages".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "mode".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "One of: pinged, phrase, every, inherit".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "phrases".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description:
+                        "Comma-separated trigger phrases; wrap a phrase in /slashes/ for a regex"
+                            .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "case_sensitive".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Match phrases case-sensitively (default: false)".to_string(),
+                    option_type: ApplicationCommandOptionType::Boolean.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "whole_word".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Only match phrases on word boundaries (default: true)"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::Boolean.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "scope".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Apply to this `channel` only, or the whole `guild` (default)"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let access_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "access".to_string(),
+            description: Some(
+                "Show or edit the roles and users Jeeves listens to or ignores".to_string(),
+            ),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "action".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "One of: show, add, remove, clear".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "list".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description:
+                        "One of: listen_to_roles, ignore_roles, listen_to_users, ignore_users"
+                            .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "role".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The role to add or remove".to_string(),
+                    option_type: ApplicationCommandOptionType::Role.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "user".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The user to add or remove".to_string(),
+                    option_type: ApplicationCommandOptionType::User.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let ratelimit_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "ratelimit".to_string(),
+            description: Some("Limit how often Jeeves replies".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "scope".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "One of: guild, channel, user, cooldown".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "capacity".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description:
+                        "Replies allowed in a burst (0 removes the limit), or cooldown seconds"
+                            .to_string(),
+                    option_type: ApplicationCommandOptionType::Integer.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "per_minute".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Replies regained per minute".to_string(),
+                    option_type: ApplicationCommandOptionType::Number.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "this_channel".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Give this channel a cooldown of its own".to_string(),
+                    option_type: ApplicationCommandOptionType::Boolean.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let capture_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "capture".to_string(),
+            description: Some(
+                "Choose how much of each channel Jeeves keeps as context".to_string(),
+            ),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "enabled".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Keep every message, not just those Jeeves replies to".to_string(),
+                    option_type: ApplicationCommandOptionType::Boolean.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "window".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "How many messages to keep per channel".to_string(),
+                    option_type: ApplicationCommandOptionType::Integer.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "regenerate_on_edit".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Write a fresh reply when a message Jeeves answered is edited"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::Boolean.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let template_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
         application_id: BOT_APPLICATION_ID.trim().to_string(),
         command: NewApplicationCommand {
-            name: "model".to_string(),
-            description: Some("Change the LLM that Jeeves will use".to_string()),
+            name: "template".to_string(),
+            description: Some("Choose or define the chat template for a local model".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "name".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The template to use, or to define".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "model".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The model to use it for (default: the current model)".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "user".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Defines a template: user turn format, with {name}, {content} and maybe {system}"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "assistant".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Jeeves' turn format, with {content}".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "system".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "System prompt format, with {content}".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "generation".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Text that opens Jeeves' turn at the end of the prompt"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "bos".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Text at the very start of the prompt".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "stop".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Comma-separated stop strings".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let fallback_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "fallback".to_string(),
+            description: Some(
+                "Set the models Jeeves falls back on when his model fails".to_string(),
+            ),
             command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
             options: Some(vec![ApplicationCommandOption {
-                name: "model".to_string(),
+                name: "models".to_string(),
                 name_localizations: None,
                 description_localizations: None,
-                description: "The model to use".to_string(),
+                description: "Comma-separated models to try in order, or `none`".to_string(),
                 option_type: ApplicationCommandOptionType::String.as_u8(),
                 required: Some(true),
             }]),
         },
     });
 
+    let stream_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "stream".to_string(),
+            description: Some("Have Jeeves post replies as he writes them".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![ApplicationCommandOption {
+                name: "enabled".to_string(),
+                name_localizations: None,
+                description_localizations: None,
+                description: "Stream replies (local models only)".to_string(),
+                option_type: ApplicationCommandOptionType::Boolean.as_u8(),
+                required: Some(true),
+            }]),
+        },
+    });
+
+    let params_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "params".to_string(),
+            description: Some("Tune how Jeeves writes his replies".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "setting".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "One of: max_tokens, temperature, top_p, presence_penalty, frequency_penalty, stop, seed, logit_bias".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "value".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The new value; leave out to clear the setting".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "this_channel".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Apply to this channel only, not the whole guild".to_string(),
+                    option_type: ApplicationCommandOptionType::Boolean.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let tools_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "tools".to_string(),
+            description: Some("List, enable or disable the tools Jeeves may use".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "action".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "One of: list, enable, disable".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "tool".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The tool to enable or disable".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let knowledge_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "knowledge".to_string(),
+            description: Some("Manage the documents Jeeves consults".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "action".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "One of: list, add, remove".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "file".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The text or markdown document to add".to_string(),
+                    option_type: ApplicationCommandOptionType::Attachment.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "name".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The document's name; defaults to the file's name".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let usage_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "usage".to_string(),
+            description: Some("See what Jeeves has spent".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![ApplicationCommandOption {
+                name: "action".to_string(),
+                name_localizations: None,
+                description_localizations: None,
+                description: "One of: show, reset, prices".to_string(),
+                option_type: ApplicationCommandOptionType::String.as_u8(),
+                required: Some(false),
+            }]),
+        },
+    });
+
+    let quota_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "quota".to_string(),
+            description: Some("Cap what Jeeves may spend".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "action".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "One of: list, set, clear, over, warn, alert".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(true),
+                },
+                ApplicationCommandOption {
+                    name: "scope".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "For set and clear: guild or user".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "period".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "For set and clear: daily or monthly".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "unit".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "For set and clear: tokens or dollars".to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "value".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "The limit; refuse or a model for over; percentages for warn"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    });
+
+    let attribution_command = HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "attribution".to_string(),
+            description: Some("Set how speakers are named in messages".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![ApplicationCommandOption {
+                name: "format".to_string(),
+                name_localizations: None,
+                description_localizations: None,
+                description: "With {name} and {content}; leave out for the default".to_string(),
+                option_type: ApplicationCommandOptionType::String.as_u8(),
+                required: Some(false),
+            }]),
+        },
+    });
+
     let commands = vec![
         help_command,
         clear_command,
         init_command,
         leave_command,
         status_command,
-        model_command,
+        models_command,
+        prompt_command,
+        respond_command,
+        access_command,
+        ratelimit_command,
+        capture_command,
+        template_command,
+        fallback_command,
+        stream_command,
+        params_command,
+        tools_command,
+        knowledge_command,
+        usage_command,
+        quota_command,
+        attribution_command,
     ];
+    let tool_commands = tool_registry()
+        .iter()
+        .filter_map(tool_slash_command)
+        .collect::<Vec<HttpApiCall>>();
 
     let discord_api_id = ProcessId::new(Some("discord_api_runner"), our.package(), our.publisher());
 
-    for command in commands {
+    for command in commands.into_iter().chain(tool_commands) {
         Request::new()
             .target((our.node.as_ref(), discord_api_id.clone()))
             .body(
@@ -185,12 +751,34 @@ fn init(our: Address) {
             .expect("jeeves: failed to register command");
     }
 
-    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
         .unwrap_or(empty_state());
+    migrate_state(&mut state);
+    // `/model` lists the registry, so it is registered once the registry is loaded
+    register_model_command(&our, &bot, &discord_api_id, &state.models)
+        .expect("jeeves: failed to register command");
+    state.memory_drive = match vfs::create_drive(our.package_id(), "memory") {
+        Ok(drive) => Some(drive),
+        Err(e) => {
+            println!("jeeves: no memory drive, so no long-term memory: {}", e);
+            None
+        }
+    };
+    state.knowledge_drive = match vfs::create_drive(our.package_id(), "knowledge") {
+        Ok(drive) => Some(drive),
+        Err(e) => {
+            println!("jeeves: no knowledge drive, so no documents: {}", e);
+            None
+        }
+    };
     set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
 
+    start_quota_timer();
+    clear_summaries_in_progress();
+
+    let mut our_channel_id: u32 = 0;
     loop {
-        match handle_jeeves_message(&our, &discord_api_id, &bot) {
+        match handle_jeeves_message(&our, &mut our_channel_id, &discord_api_id, &bot) {
             Ok(()) => {}
             Err(e) => {
                 println!("jeeves: error: {e:?}");
@@ -201,11 +789,35 @@ fn init(our: Address) {
 
 fn handle_jeeves_message(
     our: &Address,
+    our_channel_id: &mut u32,
     discord_api_id: &ProcessId,
     bot: &BotId,
 ) -> anyhow::Result<()> {
     match await_message() {
-        Ok(Message::Request { ref body, .. }) => {
+        Ok(Message::Request {
+            ref source,
+            ref body,
+            ..
+        }) => {
+            // pieces of a streamed completion
+            if source.node == our.node && source.process == provider_process(&Provider::Lccp) {
+                return handle_stream_chunk(our, bot, discord_api_id, body);
+            }
+
+            // the UI
+            if source.node == our.node
+                && source.process == ProcessId::new(Some("http_server"), "distro", "sys")
+            {
+                return handle_http_server_request(
+                    our,
+                    our_channel_id,
+                    bot,
+                    discord_api_id,
+                    source,
+                    body,
+                );
+            }
+
             // Handle Discord API events
             // Can handle any of their abundant events here, depending on your bot's perms...
             let Ok(event) = serde_json::from_slice::<GatewayReceiveEvent>(&body) else {
@@ -217,6 +829,33 @@ fn handle_jeeves_message(
             };
 
             match event {
+                GatewayReceiveEvent::Ready(ready) => {
+                    let mut state =
+                        get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+                            .unwrap_or(empty_state());
+                    println!(
+                        "jeeves: connected as {} ({})",
+                        ready.user.username, ready.user.id
+                    );
+                    state.bot_user_id = Some(ready.user.id);
+                    state.bot_username = Some(ready.user.username);
+                    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+                }
+                GatewayReceiveEvent::MessageUpdate(message) => {
+                    handle_message_update(message)?;
+                }
+                GatewayReceiveEvent::MessageDelete(deleted) => {
+                    let Some(guild_id) = deleted.guild_id else {
+                        return Ok(());
+                    };
+                    forget_messages(&guild_id, &deleted.channel_id, &[deleted.id]);
+                }
+                GatewayReceiveEvent::MessageDeleteBulk(deleted) => {
+                    let Some(guild_id) = deleted.guild_id else {
+                        return Ok(());
+                    };
+                    forget_messages(&guild_id, &deleted.channel_id, &deleted.ids);
+                }
                 GatewayReceiveEvent::InteractionCreate(interaction) => {
                     let Some(data) = interaction.data else {
                         println!("jeeves: got interaction without data: {:?}", interaction);
@@ -230,63 +869,264 @@ fn handle_jeeves_message(
                         return Ok(());
                     };
                     // create_guild_if_not_exists(&interaction.guild_id, &channel_id)?;
-                    match data.name.as_str() {
-                        "help" => {
-                            let _ = respond_with_help(
-                                &our,
-                                &bot,
-                                &discord_api_id,
-                                interaction.id,
-                                interaction.token,
-                            );
-                        }
-                        "clear" => {
-                            let _ = clear_conversation(
-                                &our,
-                                &bot,
-                                &discord_api_id,
-                                interaction.id,
-                                interaction.token,
-                                guild_id,
-                                &channel_id,
-                            );
-                        }
-                        "init" => {
-                            let _ = save_channel(
-                                &our,
-                                &bot,
-                                &discord_api_id,
-                                interaction.id,
-                                interaction.token,
-                                guild_id,
-                                channel_id,
-                            );
-                        }
-                        "leave" => {
-                            let _ = leave_channel(
-                                &our,
-                                &bot,
-                                &discord_api_id,
-                                interaction.id,
-                                interaction.token,
-                                guild_id,
-                                channel_id,
-                            );
-                        }
-                        "model" => {
-                            let _ = switch_model(
+                    let user_id = interaction
+                        .member
+                        .as_ref()
+                        .and_then(|m| m.user.as_ref())
+                        .or(interaction.user.as_ref())
+                        .map(|u| u.id.clone())
+                        .unwrap_or_default();
+                    let role_ids = interaction
+                        .member
+                        .as_ref()
+                        .map(|m| m.roles.clone())
+                        .unwrap_or_default();
+                    // those who manage the guild are always heard, so that no access
+                    // list can lock them out
+                    let manager = can_configure(
+                        interaction
+                            .member
+                            .as_ref()
+                            .and_then(|m| m.permissions.as_deref()),
+                    );
+                    if !manager && !is_open_command(&data.name) {
+                        println!("jeeves: {} may not use /{}", user_id, data.name);
+                        send_message_to_discord(
+                            "I regret, sir, that I take such instructions only from those who manage this guild."
+                                .to_string(),
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            Some(interaction.token),
+                        )?;
+                        return Ok(());
+                    }
+                    let state =
+                        get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+                            .unwrap_or(empty_state());
+                    if let Some(guild) = state.guilds.get(&guild_id) {
+                        if !manager && !is_listened_to(guild, &user_id, &role_ids) {
+                            println!("jeeves: ignoring command from {}", user_id);
+                            send_message_to_discord(
+                                "I regret, sir, that I have been instructed not to take orders from you."
+                                    .to_string(),
                                 &our,
                                 &bot,
                                 &discord_api_id,
                                 interaction.id,
-                                interaction.token,
-                                guild_id,
-                                channel_id,
-                                data,
+                                Some(interaction.token),
                             )?;
+                            return Ok(());
                         }
-                        "status" => {
-                            let _ = send_status(
+                    }
+                    let command = data.name.clone();
+                    let interaction_id = interaction.id.clone();
+                    let interaction_token = interaction.token.clone();
+                    let result = match data.name.as_str() {
+                        "help" => respond_with_help(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                        ),
+                        "clear" => clear_conversation(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            &channel_id,
+                        ),
+                        "init" => save_channel(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                        ),
+                        "leave" => leave_channel(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                        ),
+                        "model" => switch_model(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "prompt" => set_system_prompt(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "models" => list_models(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                        ),
+                        "status" => send_status(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "access" => edit_access_list(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "capture" => set_capture(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "ratelimit" => set_rate_limit(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "template" => set_chat_template(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "fallback" => set_fallback_models(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "stream" => set_streaming(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "params" => set_gen_params(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "attribution" => set_attribution(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "quota" => set_quotas(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "usage" => manage_usage(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "knowledge" => manage_knowledge(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "tools" => edit_tools(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        "respond" => set_response_schema(
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction.id,
+                            interaction.token,
+                            guild_id,
+                            channel_id,
+                            data,
+                        ),
+                        command => match find_tool_by_command(command) {
+                            Some(tool) => run_tool_command(
                                 &our,
                                 &bot,
                                 &discord_api_id,
@@ -294,10 +1134,23 @@ fn handle_jeeves_message(
                                 interaction.token,
                                 guild_id,
                                 channel_id,
+                                tool,
                                 data,
-                            )?;
-                        }
-                        _ => {}
+                            ),
+                            None => Ok(()),
+                        },
+                    };
+                    if let Err(e) = result {
+                        let error = JeevesError::from_anyhow(&e);
+                        println!("jeeves: /{} failed: {}", command, error);
+                        send_message_to_discord(
+                            error.user_message(),
+                            &our,
+                            &bot,
+                            &discord_api_id,
+                            interaction_id,
+                            Some(interaction_token),
+                        )?;
                     }
                 }
                 GatewayReceiveEvent::MessageCreate(message) => {
@@ -312,9 +1165,16 @@ fn handle_jeeves_message(
                         println!("jeeves: got message without author");
                         return Ok(());
                     };
-                    if author.username == "Jeeves" {
+                    // until Ready tells us who we are, we can't tell our own messages
+                    // from anyone else's, so let them all go by
+                    let Some(bot_user_id) = state.bot_user_id.clone() else {
+                        println!("jeeves: not ready; ignoring message {}", message.id);
+                        return Ok(());
+                    };
+                    if author.id == bot_user_id {
                         return Ok(());
                     }
+                    let bot_name = state.bot_username.clone().unwrap_or("Jeeves".to_string());
                     let Some(guild) = state.guilds.get_mut(&guild_id) else {
                         // println!("jeeves: message from outside guild: {}", guild_id);
                         return Ok(());
@@ -323,8 +1183,12 @@ fn handle_jeeves_message(
                         // println!("jeeves: got message in channel not in our channels: {}", message.channel_id);
                         return Ok(());
                     }
-                    if guild.cooldown > 0 {
-                        println!("jeeves: guild is on cooldown: {}", guild.id);
+                    let role_ids = message
+                        .member
+                        .as_ref()
+                        .map(|m| m.roles.clone())
+                        .unwrap_or_default();
+                    if !is_listened_to(guild, &author.id, &role_ids) {
                         return Ok(());
                     }
                     let Some(content) = message.content else {
@@ -332,175 +1196,379 @@ fn handle_jeeves_message(
                         return Ok(());
                     };
 
-                    let mut should_respond = false;
-                    if content.to_lowercase().contains("jeeves") {
-                        should_respond = true;
-                    } else if let Some(mentions) = message.mentions {
-                        if mentions.iter().any(|u| u.username == "Jeeves") {
-                            should_respond = true;
-                        }
+                    // we get dupe message events sometimes
+                    if has_utterance(guild, &message.channel_id, &message.id) {
+                        return Ok(());
+                    }
+
+                    let mentions = message.mentions.unwrap_or_default();
+                    let images = image_attachments(&message.attachments.unwrap_or_default());
+                    if !images.is_empty() {
+                        println!(
+                            "jeeves: message {} has {} image(s)",
+                            message.id,
+                            images.len()
+                        );
+                    }
+                    let utterance = Utterance {
+                        id: Some(message.id.clone()),
+                        username: author.username.clone(),
+                        content: humanize_mentions(&content, &mentions, &bot_user_id, &bot_name),
+                        in_reply_to: None,
+                        model: None,
+                        images,
+                        from_bot: false,
                     };
+                    let captured = guild.capture.enabled;
+                    if captured {
+                        let dropped = push_utterance(guild, &message.channel_id, utterance.clone());
+                        remember_utterances(&state.models, guild, &message.channel_id, dropped)?;
+                        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+                    }
 
-                    if !should_respond {
+                    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+                        return Ok(());
+                    };
+                    if on_cooldown(guild, &message.channel_id) {
+                        println!(
+                            "jeeves: on cooldown in {}: {}",
+                            message.channel_id, guild.id
+                        );
                         return Ok(());
                     }
 
-                    // we get dupe message events sometimes
-                    if guild
-                        .message_log
-                        .get(&message.channel_id)
-                        .unwrap_or(&vec![])
-                        .iter()
-                        .any(|m| m.id == Some(message.id.clone()))
-                    {
+                    // only a real mention is a ping; guilds that want Jeeves to answer
+                    // to his name can make it a trigger phrase with `/respond`
+                    let pinged = mentions.iter().any(|u| u.id == bot_user_id)
+                        || is_bot_mention(&content, &bot_user_id);
+
+                    let schema = response_schema_for_channel(guild, &message.channel_id);
+                    if !should_respond(schema, &utterance.content, pinged) {
                         return Ok(());
                     }
 
-                    guild
-                        .message_log
-                        .entry(message.channel_id.clone())
-                        .or_insert(vec![])
-                        .push(Utterance {
-                            id: Some(message.id.clone()),
-                            username: author.username,
-                            content,
-                        });
+                    if let Err(limited) = try_take_token(guild, &message.channel_id, &author.id) {
+                        println!("jeeves: rate limited in guild {}", guild.id);
+                        let warn = should_warn(guild, &limited, &message.channel_id, &author.id);
+                        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+                        if warn {
+                            send_message_to_discord(
+                                rate_limited_message(&limited),
+                                our,
+                                bot,
+                                discord_api_id,
+                                message.channel_id.clone(),
+                                None,
+                            )?;
+                        }
+                        return Ok(());
+                    }
+
+                    let pending = PendingReply::new(
+                        guild_id.clone(),
+                        message.channel_id.clone(),
+                        message.id.clone(),
+                        Some(author.id.clone()),
+                        false,
+                    );
+                    let pending = match apply_quotas(guild, pending) {
+                        Ok(pending) => pending,
+                        Err(refusal) => {
+                            return send_message_to_discord(
+                                refusal,
+                                our,
+                                bot,
+                                discord_api_id,
+                                message.channel_id.clone(),
+                                None,
+                            );
+                        }
+                    };
+
+                    if !captured {
+                        let dropped = push_utterance(guild, &message.channel_id, utterance);
+                        remember_utterances(&state.models, guild, &message.channel_id, dropped)?;
+                    }
                     set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
 
-                    let completion =
-                        create_chat_completion_for_guild_channel(&guild_id, &message.channel_id);
-                    if let Err(e) = completion {
-                        send_message_to_discord(
-                            format!("[ERROR: fetching completion failed: {}]", e).to_string(),
+                    if let Err(e) = request_completion_for_guild_channel(pending.clone()) {
+                        handle_completion_failure(
                             our,
                             bot,
                             discord_api_id,
-                            message.channel_id.clone(),
-                            None,
+                            pending,
+                            JeevesError::from_anyhow(&e),
                         )?;
-                        return Ok(());
                     }
+                }
+                _ => {}
+            }
+        }
+        Ok(Message::Response {
+            ref body,
+            ref context,
+            ..
+        }) => {
+            // Responses with a context are ones we asked for, e.g. popping Timers.
+            // Each context says what the response is for, and the data necessary to act on it.
+            let Some(context) = context
+                .as_ref()
+                .and_then(|c| serde_json::from_slice::<ResponseContext>(c).ok())
+            else {
+                println!("jeeves: got response: {:?}", String::from_utf8_lossy(body));
+                return Ok(());
+            };
 
-                    let Ok(completion) = completion else {
-                        return Ok(());
-                    };
-
-                    println!("jeeves: got completion: {}", completion);
-
-                    send_message_to_discord(
-                        completion.clone(),
+            match context {
+                ResponseContext::QuotaReset => handle_quota_reset(),
+                ResponseContext::Completion(pending) => {
+                    handle_completion_response(our, bot, discord_api_id, pending, body)?;
+                }
+                ResponseContext::RetryCompletion(pending) => {
+                    if let Err(e) = request_completion_for_guild_channel(pending.clone()) {
+                        handle_completion_failure(
+                            our,
+                            bot,
+                            discord_api_id,
+                            pending,
+                            JeevesError::from_anyhow(&e),
+                        )?;
+                    }
+                }
+                ResponseContext::PlaceholderPosted(key) => {
+                    handle_placeholder_posted(our, bot, discord_api_id, key, body)?;
+                }
+                ResponseContext::StreamEditTick(key) => {
+                    handle_stream_edit_tick(our, bot, discord_api_id, key)?;
+                }
+                ResponseContext::Summary(request) => {
+                    handle_summary_response(request, body)?;
+                }
+                ResponseContext::MemoryEmbedding(snippet) => {
+                    handle_memory_embedding(snippet, body)?;
+                }
+                ResponseContext::KnowledgeEmbedding(passage) => {
+                    handle_knowledge_embedding(passage, Some(body))?;
+                }
+                ResponseContext::Delivery(delivery) => {
+                    handle_delivery_response(delivery, body)?;
+                }
+                ResponseContext::RetryDelivery(delivery) => {
+                    post_delivery(our, bot, discord_api_id, delivery)?;
+                }
+                ResponseContext::RecallEmbedding(pending, provider) => {
+                    if let Err(e) = handle_recall_embedding(pending.clone(), provider, Some(body)) {
+                        handle_completion_failure(
+                            our,
+                            bot,
+                            discord_api_id,
+                            pending,
+                            JeevesError::from_anyhow(&e),
+                        )?;
+                    }
+                }
+                ResponseContext::DocumentFetched(fetch) => {
+                    handle_document_fetched(our, bot, discord_api_id, fetch, Some(body))?;
+                }
+                ResponseContext::ImageFetched { message_id, index } => {
+                    for pending in handle_image_fetched(&message_id, index, Some(body)) {
+                        if let Err(e) = request_completion_for_guild_channel(pending.clone()) {
+                            handle_completion_failure(
+                                our,
+                                bot,
+                                discord_api_id,
+                                pending,
+                                JeevesError::from_anyhow(&e),
+                            )?;
+                        }
+                    }
+                }
+            }
+        }
+        Err(send_error) => {
+            // a request we sent timed out or could not be delivered
+            let Some(context) = send_error
+                .context()
+                .and_then(|c| serde_json::from_slice::<ResponseContext>(c).ok())
+            else {
+                // only Discord calls go out without a context
+                println!(
+                    "jeeves: {}",
+                    JeevesError::DiscordDelivery(format!("{:?}", send_error.kind()))
+                );
+                return Ok(());
+            };
+            match context {
+                ResponseContext::QuotaReset => start_quota_timer(),
+                ResponseContext::Completion(pending) => {
+                    handle_completion_failure(
                         our,
                         bot,
                         discord_api_id,
-                        message.channel_id.clone(),
-                        None,
+                        pending,
+                        JeevesError::from_send_error(send_error.kind()),
                     )?;
-
-                    let mut state =
-                        get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
-                            .unwrap_or(empty_state());
-                    let Some(guild) = state.guilds.get_mut(&guild_id) else {
-                        return Ok(());
-                    };
-                    guild
-                        .message_log
-                        .entry(message.channel_id.clone())
-                        .or_insert(vec![])
-                        .push(Utterance {
-                            id: None,
-                            username: "Jeeves".to_string(),
-                            content: completion,
-                        });
-                    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
                 }
-                _ => {}
+                ResponseContext::RetryCompletion(pending) => {
+                    handle_completion_failure(
+                        our,
+                        bot,
+                        discord_api_id,
+                        pending,
+                        JeevesError::from_send_error(send_error.kind()),
+                    )?;
+                }
+                ResponseContext::PlaceholderPosted(key) => {
+                    // without the placeholder's id the reply is posted afresh when done
+                    handle_placeholder_posted(our, bot, discord_api_id, key, &[])?;
+                }
+                ResponseContext::StreamEditTick(key) => {
+                    handle_stream_edit_tick(our, bot, discord_api_id, key)?;
+                }
+                ResponseContext::Summary(request) => {
+                    println!("jeeves: summary failed: {:?}", send_error.kind());
+                    abandon_summary(request);
+                }
+                ResponseContext::MemoryEmbedding(_) => {
+                    println!("jeeves: could not embed a memory: {:?}", send_error.kind());
+                }
+                ResponseContext::KnowledgeEmbedding(passage) => {
+                    println!(
+                        "jeeves: could not embed a passage of {}: {:?}",
+                        passage.document,
+                        send_error.kind()
+                    );
+                    handle_knowledge_embedding(passage, None)?;
+                }
+                ResponseContext::Delivery(delivery) | ResponseContext::RetryDelivery(delivery) => {
+                    println!("jeeves: could not post a reply: {:?}", send_error.kind());
+                    handle_delivery_failure(delivery)?;
+                }
+                ResponseContext::RecallEmbedding(pending, provider) => {
+                    // answer without memories rather than not at all
+                    if let Err(e) = handle_recall_embedding(pending.clone(), provider, None) {
+                        handle_completion_failure(
+                            our,
+                            bot,
+                            discord_api_id,
+                            pending,
+                            JeevesError::from_anyhow(&e),
+                        )?;
+                    }
+                }
+                ResponseContext::DocumentFetched(fetch) => {
+                    println!(
+                        "jeeves: could not fetch {}: {:?}",
+                        fetch.name,
+                        send_error.kind()
+                    );
+                    handle_document_fetched(our, bot, discord_api_id, fetch, None)?;
+                }
+                ResponseContext::ImageFetched { message_id, index } => {
+                    for pending in handle_image_fetched(&message_id, index, None) {
+                        if let Err(e) = request_completion_for_guild_channel(pending.clone()) {
+                            handle_completion_failure(
+                                our,
+                                bot,
+                                discord_api_id,
+                                pending,
+                                JeevesError::from_anyhow(&e),
+                            )?;
+                        }
+                    }
+                }
             }
         }
-        Ok(Message::Response { ref body, .. }) => {
-            // Responses currently only come in from popping Timers.
-            // Timers are right now used for queueing multi-stage messages.
-            // Each Timer has a context of what message to send, and the data necessary to send it.
-
-            // TODO shrimplement
-
-            println!("jeeves: got response: {:?}", String::from_utf8_lossy(body));
-        }
         _ => {}
     }
     Ok(())
 }
 
-fn create_chat_completion_for_guild_channel(
-    guild_id: &String,
-    channel_id: &String,
-) -> anyhow::Result<String> {
-    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+fn forget_messages(guild_id: &String, channel_id: &String, message_ids: &[String]) {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
         .unwrap_or(empty_state());
-    let Some(guild) = state.guilds.get(guild_id) else {
-        return Ok("".to_string());
+    let Some(guild) = state.guilds.get_mut(guild_id) else {
+        return;
     };
-    if !guild.our_channels.contains(&channel_id) {
-        return Ok("".to_string());
-    }
+    remove_utterances(guild, channel_id, message_ids);
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+}
 
-    let mut messages: Vec<(String, String)> =
-        vec![("system".to_string(), guild.system_prompt.clone())];
-    for msg in guild.message_log.get(channel_id).unwrap_or(&vec![]).clone() {
-        messages.push((msg.username.clone(), msg.content.clone()));
+/// Keep the log in step with edits, and if the guild asks for it,
+/// rewrite our reply to an edited message.
+fn handle_message_update(message: discord_api::Message) -> anyhow::Result<()> {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild_id) = message.guild_id else {
+        return Ok(());
+    };
+    // edits that only touch embeds and the like come without content
+    let Some(content) = message.content else {
+        return Ok(());
+    };
+    let Some(bot_user_id) = state.bot_user_id.clone() else {
+        return Ok(());
+    };
+    let bot_name = state.bot_username.clone().unwrap_or("Jeeves".to_string());
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        return Ok(());
+    };
+    let mentions = message.mentions.unwrap_or_default();
+    let humanized = humanize_mentions(&content, &mentions, &bot_user_id, &bot_name);
+    if !edit_utterance(guild, &message.channel_id, &message.id, humanized.clone()) {
+        return Ok(());
     }
+    let regenerate =
+        guild.regenerate_on_edit && reply_to(guild, &message.channel_id, &message.id).is_some();
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
 
-    let mut model = guild.llm.clone();
-    if model.len() == 0 {
-        model = "gpt-3.5-turbo".to_string();
+    if !regenerate {
+        return Ok(());
     }
-    create_chat_completion(messages, model)
-}
-
-fn create_chat_completion(
-    messages: Vec<(String, String)>,
-    model: String,
-) -> anyhow::Result<String> {
-    let new_messages = messages
-        .iter()
-        .map(|m| OpenaiMessage {
-            role: if m.0 == "Jeeves" {
-                "assistant".to_string()
-            } else if m.0 == "system" {
-                "system".to_string()
-            } else {
-                "user".to_string()
-            },
-            content: format!("[{}]: {}", m.0, m.1),
-        })
-        .collect::<Vec<OpenaiMessage>>();
-    let chat_params = ChatParams {
-        model,
-        messages: new_messages,
-        max_tokens: Some(900),
-        temperature: Some(1.25),
-        ..Default::default()
+    let Some(author) = message.author else {
+        return Ok(());
     };
-    let chat_request = ChatRequest {
-        params: chat_params,
-        api_key: OPENAI_API_KEY.trim().to_string(),
+    let Some(guild) = state.guilds.get_mut(&guild_id) else {
+        return Ok(());
     };
-    let request = LLMRequest::Chat(chat_request);
-    let msg = Request::new()
-        .target(Address::new(
-            "our",
-            ProcessId::new(Some("openai"), "llm", "kinode"),
-        ))
-        .body(request.to_bytes())
-        .send_and_await_response(30)??;
-    let response = LLMResponse::parse(msg.body())?;
-    if let LLMResponse::Chat(chat) = response {
-        let completion = chat.to_chat_response();
-        let t = completion.to_string().replace("[Jeeves]:", "");
-        println!("jeeves says: {}", t);
-        Ok(t)
-    } else {
-        Err(anyhow::Error::msg("Error querying OpenAI: wrong result"))
+
+    // an edit earns a reply only as a new message would
+    let role_ids = message
+        .member
+        .as_ref()
+        .map(|m| m.roles.clone())
+        .unwrap_or_default();
+    if !guild.our_channels.contains(&message.channel_id)
+        || !is_listened_to(guild, &author.id, &role_ids)
+        || on_cooldown(guild, &message.channel_id)
+    {
+        return Ok(());
     }
+    let pinged =
+        mentions.iter().any(|u| u.id == bot_user_id) || is_bot_mention(&content, &bot_user_id);
+    let schema = response_schema_for_channel(guild, &message.channel_id);
+    if !should_respond(schema, &humanized, pinged) {
+        return Ok(());
+    }
+    if try_take_token(guild, &message.channel_id, &author.id).is_err() {
+        println!("jeeves: rate limited in guild {}; not rewriting", guild.id);
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+        return Ok(());
+    }
+
+    let pending = PendingReply::new(
+        guild_id.clone(),
+        message.channel_id,
+        message.id,
+        Some(author.id),
+        true,
+    );
+    let pending = match apply_quotas(guild, pending) {
+        Ok(pending) => pending,
+        Err(_) => return Ok(()),
+    };
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+    request_completion_for_guild_channel(pending)
 }
 
 fn init_discord_api(
@@ -544,6 +1612,8 @@ fn handle_frontend_request(
 fn handle_http_server_request(
     our: &Address,
     our_channel_id: &mut u32,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
     source: &Address,
     body: &[u8],
 ) -> anyhow::Result<()> {
@@ -579,6 +1649,58 @@ fn handle_http_server_request(
 
                     send_response(StatusCode::OK, Some(headers), state);
                 }
+                // Add a document to a guild's knowledge
+                "POST" if request.path()?.ends_with("/knowledge") => {
+                    let Some(blob) = get_blob() else {
+                        send_response(StatusCode::BAD_REQUEST, None, vec![]);
+                        return Ok(());
+                    };
+                    let Ok(upload) = serde_json::from_slice::<DocumentUpload>(&blob.bytes) else {
+                        send_response(StatusCode::BAD_REQUEST, None, vec![]);
+                        return Ok(());
+                    };
+                    let state =
+                        get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+                            .unwrap_or(empty_state());
+                    let Some(guild) = state.guilds.get(&upload.guild_id) else {
+                        send_response(StatusCode::NOT_FOUND, None, vec![]);
+                        return Ok(());
+                    };
+                    if upload.text.len() > KNOWLEDGE_MAX_DOCUMENT_BYTES {
+                        send_response(StatusCode::BAD_REQUEST, None, vec![]);
+                        return Ok(());
+                    }
+                    match add_document(&state.models, guild, &upload.name, &upload.text) {
+                        Ok(_) => send_response(StatusCode::CREATED, None, vec![]),
+                        Err(e) => {
+                            println!("jeeves: could not add {}: {}", upload.name, e);
+                            send_response(StatusCode::INTERNAL_SERVER_ERROR, None, vec![]);
+                        }
+                    }
+                }
+                // Change the model registry; the path is authenticated, so
+                // only the node's owner gets here
+                "POST" if request.path()?.ends_with("/models") => {
+                    let Some(blob) = get_blob() else {
+                        send_response(StatusCode::BAD_REQUEST, None, vec![]);
+                        return Ok(());
+                    };
+                    let Ok(edit) = serde_json::from_slice::<ModelEdit>(&blob.bytes) else {
+                        send_response(StatusCode::BAD_REQUEST, None, vec![]);
+                        return Ok(());
+                    };
+                    let mut state =
+                        get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+                            .unwrap_or(empty_state());
+                    if let Err(e) = apply_model_edit(&mut state, edit) {
+                        println!("jeeves: could not change the models: {}", e);
+                        send_response(StatusCode::BAD_REQUEST, None, e.to_string().into_bytes());
+                        return Ok(());
+                    }
+                    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+                    register_model_command(our, bot, discord_api_id, &state.models)?;
+                    send_response(StatusCode::OK, None, vec![]);
+                }
                 _ => {
                     // Method not allowed
                     send_response(StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
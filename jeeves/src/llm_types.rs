@@ -353,7 +353,7 @@ pub mod openai {
         #[serde(skip_serializing_if = "Option::is_none")]
         pub top_p: Option<f64>,
         #[serde(skip_serializing_if = "Option::is_none")]
-        pub tools: Option<Vec<String>>,
+        pub tools: Option<Vec<Tool>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub tool_choice: Option<ToolChoice>,
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -362,7 +362,91 @@ pub mod openai {
     #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
     pub struct Message {
         pub role: String,
-        pub content: String,
+        /// Empty when an assistant message only calls tools.
+        #[serde(default)]
+        pub content: Option<Content>,
+        /// Who is speaking, for conversations with several users.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub tool_calls: Option<Vec<ToolCall>>,
+        /// On a "tool" message, the call it answers.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub tool_call_id: Option<String>,
+    }
+
+    /// Plain text, or text and images for models that can see.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(untagged)]
+    pub enum Content {
+        Text(String),
+        Parts(Vec<ContentPart>),
+    }
+
+    impl Content {
+        /// The text of the content, without its images.
+        pub fn text(&self) -> String {
+            match self {
+                Content::Text(text) => text.clone(),
+                Content::Parts(parts) => parts
+                    .iter()
+                    .filter_map(|p| match p {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+            }
+        }
+    }
+
+    impl From<String> for Content {
+        fn from(text: String) -> Self {
+            Content::Text(text)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum ContentPart {
+        Text { text: String },
+        ImageUrl { image_url: ImageUrl },
+    }
+
+    #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct ImageUrl {
+        /// A link, or the image itself as a `data:` URL.
+        pub url: String,
+    }
+
+    #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Tool {
+        #[serde(rename = "type")]
+        pub type_field: String,
+        pub function: FunctionDefinition,
+    }
+
+    #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct FunctionDefinition {
+        pub name: String,
+        pub description: String,
+        /// A JSON schema for the arguments.
+        pub parameters: serde_json::Value,
+    }
+
+    #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct ToolCall {
+        pub id: String,
+        #[serde(rename = "type")]
+        pub type_field: String,
+        pub function: FunctionCall,
+    }
+
+    #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct FunctionCall {
+        pub name: String,
+        /// JSON-encoded arguments, as written by the model.
+        pub arguments: String,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -379,11 +463,19 @@ pub mod openai {
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-    #[serde(untagged)]
-    pub enum ToolChoice {
+    #[serde(rename_all = "lowercase")]
+    pub enum ToolChoiceMode {
         None,
         Auto,
+        Required,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(untagged)]
+    pub enum ToolChoice {
+        Mode(ToolChoiceMode),
         SpecificFunction {
+            #[serde(rename = "type")]
             type_field: String,
             function: Function,
         },
@@ -406,8 +498,21 @@ pub mod openai {
     }
 
     impl ChatResponse {
-        pub fn to_chat_response(&self) -> String {
-            self.choices[0].message.content.clone()
+        pub fn to_chat_response(&self) -> Result<String, crate::errors::JeevesError> {
+            let Some(choice) = self.choices.first() else {
+                return Err(crate::errors::JeevesError::Malformed(
+                    "no choices in the response".to_string(),
+                ));
+            };
+            if choice.finish_reason == "content_filter" {
+                return Err(crate::errors::JeevesError::ContentFilter);
+            }
+            Ok(choice
+                .message
+                .content
+                .as_ref()
+                .map(|c| c.text())
+                .unwrap_or_default())
         }
     }
 
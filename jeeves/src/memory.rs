@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::completion::*;
+use crate::consts::*;
+use crate::empty_state;
+use crate::knowledge::*;
+use crate::models::*;
+use crate::providers::*;
+use crate::types::*;
+use crate::usage::*;
+use kinode_process_lib::{get_typed_state, println, vfs, Address, Request};
+
+/// Something said in a channel, kept with its embedding so it can be found again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryEntry {
+    pub text: String,
+    pub embedder: String,
+    pub embedding: Vec<f32>,
+}
+
+/// A channel's memories, oldest first. On our VFS drive they are a file of
+/// bincode records, appended to as memories come in; `on_disk` counts the
+/// records there, which may include some already trimmed from `entries`.
+#[derive(Debug, Clone, Default)]
+struct ChannelMemory {
+    entries: Vec<MemoryEntry>,
+    on_disk: usize,
+}
+
+/// The memories of each channel we have looked at, by guild and channel ID,
+/// so that replies don't read them from disk.
+fn memories() -> MutexGuard<'static, HashMap<(String, String), ChannelMemory>> {
+    static MEMORIES: OnceLock<Mutex<HashMap<(String, String), ChannelMemory>>> = OnceLock::new();
+    MEMORIES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+fn memory_drive() -> Option<String> {
+    get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state())
+        .memory_drive
+}
+
+fn memory_path(drive: &str, guild_id: &String, channel_id: &String) -> String {
+    format!("{}/{}-{}.bin", drive, guild_id, channel_id)
+}
+
+fn read_entries(bytes: &[u8]) -> Vec<MemoryEntry> {
+    let mut reader = bytes;
+    let mut entries = vec![];
+    while !reader.is_empty() {
+        match bincode::deserialize_from::<_, MemoryEntry>(&mut reader) {
+            Ok(entry) => entries.push(entry),
+            // a record cut short as it was written
+            Err(_) => break,
+        }
+    }
+    entries
+}
+
+/// Memories used to be kept as one JSON file per guild. Split it into its
+/// channels' files the first time any of them is wanted.
+fn split_legacy_index(drive: &str, guild_id: &String) {
+    #[derive(Deserialize)]
+    struct LegacyEntry {
+        channel_id: String,
+        text: String,
+        embedder: String,
+        embedding: Vec<f32>,
+    }
+    #[derive(Deserialize)]
+    struct LegacyIndex {
+        entries: Vec<LegacyEntry>,
+    }
+
+    let path = format!("{}/{}.json", drive, guild_id);
+    let Ok(file) = vfs::open_file(&path, false) else {
+        return;
+    };
+    let index = file
+        .read()
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<LegacyIndex>(&bytes).ok());
+    if let Some(index) = index {
+        let mut by_channel: HashMap<String, Vec<u8>> = HashMap::new();
+        for e in index.entries {
+            let entry = MemoryEntry {
+                text: e.text,
+                embedder: e.embedder,
+                embedding: e.embedding,
+            };
+            let bytes = by_channel.entry(e.channel_id).or_default();
+            if bincode::serialize_into(bytes, &entry).is_err() {
+                println!(
+                    "jeeves: could not carry over a memory of guild {}",
+                    guild_id
+                );
+            }
+        }
+        for (channel_id, bytes) in by_channel {
+            let written = vfs::open_file(&memory_path(drive, guild_id, &channel_id), true)
+                .and_then(|file| file.write(&bytes));
+            if let Err(e) = written {
+                println!(
+                    "jeeves: could not carry over the memories of {}: {}",
+                    channel_id, e
+                );
+                return;
+            }
+        }
+    }
+    let _ = vfs::remove_file(&path);
+}
+
+/// The channel's memories, read from disk if they aren't in `cache` yet.
+fn channel_memory<'a>(
+    cache: &'a mut HashMap<(String, String), ChannelMemory>,
+    guild_id: &String,
+    channel_id: &String,
+) -> &'a mut ChannelMemory {
+    cache
+        .entry((guild_id.clone(), channel_id.clone()))
+        .or_insert_with(|| {
+            let Some(drive) = memory_drive() else {
+                return ChannelMemory::default();
+            };
+            split_legacy_index(&drive, guild_id);
+            let mut entries = vfs::open_file(&memory_path(&drive, guild_id, channel_id), true)
+                .and_then(|file| file.read())
+                .map(|bytes| read_entries(&bytes))
+                .unwrap_or_default();
+            let on_disk = entries.len();
+            if entries.len() > MEMORY_MAX_ENTRIES {
+                entries.drain(..entries.len() - MEMORY_MAX_ENTRIES);
+            }
+            ChannelMemory { entries, on_disk }
+        })
+}
+
+/// Memories and documents are embedded by the guild's embedder, pinned when the
+/// guild was set up so that they can all be compared whatever model it switches to.
+pub fn memory_provider(models: &HashMap<String, ModelInfo>, guild: &GuildInfo) -> Provider {
+    if let Some(embedder) = &guild.embedder {
+        return embedder.clone();
+    }
+    if guild.llm.is_empty() {
+        return provider_for_model(models, DEFAULT_MODEL);
+    }
+    provider_for_model(models, &guild.llm)
+}
+
+fn embed(snippet: MemorySnippet) -> anyhow::Result<()> {
+    Request::new()
+        .target(Address::new("our", provider_process(&snippet.provider)))
+        .body(build_embedding_request(&snippet.provider, &snippet.text))
+        .context(serde_json::to_vec(&ResponseContext::MemoryEmbedding(
+            snippet,
+        ))?)
+        .expects_response(LLM_TIMEOUT_SECS)
+        .send()
+}
+
+/// Embed utterances that have left a channel's log, a few at a time, so they can
+/// be recalled later.
+pub fn remember_utterances(
+    models: &HashMap<String, ModelInfo>,
+    guild: &GuildInfo,
+    channel_id: &String,
+    utterances: Vec<Utterance>,
+) -> anyhow::Result<()> {
+    for chunk in utterances.chunks(MEMORY_SNIPPET_UTTERANCES) {
+        let text = chunk
+            .iter()
+            .map(|u| format!("[{}]: {}", u.username, u.content))
+            .collect::<Vec<String>>()
+            .join("\n");
+        embed(MemorySnippet {
+            guild_id: guild.id.clone(),
+            channel_id: channel_id.clone(),
+            text,
+            provider: memory_provider(models, guild),
+        })?;
+    }
+    Ok(())
+}
+
+pub fn remember_summary(
+    models: &HashMap<String, ModelInfo>,
+    guild: &GuildInfo,
+    channel_id: &String,
+    summary: &str,
+) -> anyhow::Result<()> {
+    embed(MemorySnippet {
+        guild_id: guild.id.clone(),
+        channel_id: channel_id.clone(),
+        text: format!("(summary of an earlier conversation) {}", summary),
+        provider: memory_provider(models, guild),
+    })
+}
+
+pub fn handle_memory_embedding(snippet: MemorySnippet, body: &[u8]) -> anyhow::Result<()> {
+    let embedding = match parse_embedding_response(&snippet.provider, body) {
+        Ok(embedding) => embedding,
+        Err(e) => {
+            println!("jeeves: could not embed a memory: {}", e);
+            return Ok(());
+        }
+    };
+    record_embedding_usage(
+        &snippet.guild_id,
+        Some(&snippet.channel_id),
+        None,
+        &snippet.provider,
+        &[&snippet.text],
+    );
+    let Some(drive) = memory_drive() else {
+        return Err(anyhow::anyhow!("no memory drive"));
+    };
+    let entry = MemoryEntry {
+        text: snippet.text,
+        embedder: embedder_name(&snippet.provider),
+        embedding,
+    };
+    let mut cache = memories();
+    let memory = channel_memory(&mut cache, &snippet.guild_id, &snippet.channel_id);
+    // the oldest memories to let go of to make room for this one
+    let excess = (memory.entries.len() + 1).saturating_sub(MEMORY_MAX_ENTRIES);
+
+    // the cache changes only once the disk has, so the two never disagree
+    let path = memory_path(&drive, &snippet.guild_id, &snippet.channel_id);
+    // the file only grows, so once it holds twice what we keep, rewrite it with just those
+    if memory.on_disk >= 2 * MEMORY_MAX_ENTRIES {
+        let mut bytes = vec![];
+        for kept in memory.entries[excess..].iter().chain([&entry]) {
+            bincode::serialize_into(&mut bytes, kept)?;
+        }
+        vfs::open_file(&path, true)?.write(&bytes)?;
+        memory.on_disk = memory.entries.len() - excess + 1;
+    } else {
+        vfs::open_file(&path, true)?.append(&bincode::serialize(&entry)?)?;
+        memory.on_disk += 1;
+    }
+    memory.entries.drain(..excess);
+    memory.entries.push(entry);
+    Ok(())
+}
+
+pub fn forget_channel_memory(guild_id: &String, channel_id: &String) -> anyhow::Result<()> {
+    memories().remove(&(guild_id.clone(), channel_id.clone()));
+    let Some(drive) = memory_drive() else {
+        return Ok(());
+    };
+    split_legacy_index(&drive, guild_id);
+    let path = memory_path(&drive, guild_id, channel_id);
+    if vfs::open_file(&path, false).is_ok() {
+        vfs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+fn has_memories(guild_id: &String, channel_id: &String, embedder: &str) -> bool {
+    channel_memory(&mut memories(), guild_id, channel_id)
+        .entries
+        .iter()
+        .any(|e| e.embedder == embedder)
+}
+
+/// Look for memories, and passages of the guild's documents, relevant to the
+/// message being answered. The reply carries on, with whatever was found, once the
+/// message's embedding comes back.
+pub fn request_recall(
+    models: &HashMap<String, ModelInfo>,
+    guild: &GuildInfo,
+    pending: PendingReply,
+) -> anyhow::Result<()> {
+    let provider = memory_provider(models, guild);
+    let embedder = embedder_name(&provider);
+    let has_memories = has_memories(&guild.id, &pending.channel_id, &embedder);
+    let log = guild
+        .message_log
+        .get(&pending.channel_id)
+        .cloned()
+        .unwrap_or(vec![]);
+    let query = log
+        .iter()
+        .find(|u| u.id.as_ref() == Some(&pending.message_id))
+        .or(log.last())
+        .map(|u| u.content.clone());
+    let has_knowledge = has_knowledge(&guild.id, &provider);
+    let Some(query) = query.filter(|_| has_memories || has_knowledge) else {
+        return request_completion_for_guild_channel(PendingReply {
+            memories: Some(vec![]),
+            ..pending
+        });
+    };
+    // the response doesn't carry the query, so charge for it as it goes
+    record_embedding_usage(
+        &guild.id,
+        Some(&pending.channel_id),
+        pending.user_id.as_ref(),
+        &provider,
+        &[&query],
+    );
+    Request::new()
+        .target(Address::new("our", provider_process(&provider)))
+        .body(build_embedding_request(&provider, &query))
+        .context(serde_json::to_vec(&ResponseContext::RecallEmbedding(
+            pending, provider,
+        ))?)
+        .expects_response(LLM_TIMEOUT_SECS)
+        .send()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// The channel's memories most like `query`. Memories of other channels are left
+/// alone, as they may be private to those who can see them.
+pub fn recall(
+    guild_id: &String,
+    channel_id: &String,
+    provider: &Provider,
+    query: &[f32],
+) -> Vec<String> {
+    let embedder = embedder_name(provider);
+    let mut cache = memories();
+    let mut scored = channel_memory(&mut cache, guild_id, channel_id)
+        .entries
+        .iter()
+        .filter(|e| e.embedder == embedder)
+        .map(|e| (cosine_similarity(&e.embedding, query), e.text.clone()))
+        .filter(|(score, _)| *score >= MEMORY_MIN_SIMILARITY)
+        .collect::<Vec<(f32, String)>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+        .into_iter()
+        .take(MEMORY_RECALL_COUNT)
+        .map(|(_, text)| text)
+        .collect()
+}
+
+/// Carry on with the reply, with whatever the message's embedding brings to mind.
+pub fn handle_recall_embedding(
+    pending: PendingReply,
+    provider: Provider,
+    body: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    let (memories, references) = match body.map(|b| parse_embedding_response(&provider, b)) {
+        Some(Ok(query)) => (
+            recall(&pending.guild_id, &pending.channel_id, &provider, &query),
+            consult_knowledge(&pending.guild_id, &provider, &query),
+        ),
+        Some(Err(e)) => {
+            println!("jeeves: could not embed for recall: {}", e);
+            (vec![], vec![])
+        }
+        None => (vec![], vec![]),
+    };
+    if !memories.is_empty() || !references.is_empty() {
+        println!(
+            "jeeves: recalled {} memories and {} passages",
+            memories.len(),
+            references.len()
+        );
+    }
+    request_completion_for_guild_channel(PendingReply {
+        memories: Some(memories),
+        references,
+        ..pending
+    })
+}
@@ -0,0 +1,181 @@
+use crate::memory::*;
+use crate::models::*;
+use crate::types::*;
+use kinode_process_lib::println;
+
+/// Bumped with each migration added to `migrate_state`.
+pub const STATE_VERSION: u32 = 6;
+
+/// Bring state saved by an older Jeeves up to date. Run once, at start, before
+/// anything else reads the state.
+pub fn migrate_state(state: &mut JeevesState) {
+    if state.version < 1 {
+        // guilds used to be created with `EveryMessage`, which went unheeded:
+        // they were only answered when Jeeves was named, so keep them that way
+        for guild in state.guilds.values_mut() {
+            if matches!(guild.response_schema, BotResponseSchema::EveryMessage) {
+                println!(
+                    "jeeves: guild {} was saved replying to every message; now replying when pinged",
+                    guild.id
+                );
+                guild.response_schema = BotResponseSchema::Pinged;
+            }
+        }
+    }
+    if state.version < 2 {
+        // capturing every message used to be on unless turned off, so no guild
+        // can be said to have asked for it
+        for guild in state.guilds.values_mut() {
+            if guild.capture.enabled {
+                println!(
+                    "jeeves: no longer capturing every message in guild {}; /capture turns it back on",
+                    guild.id
+                );
+                guild.capture.enabled = false;
+            }
+        }
+    }
+    if state.version < 3 {
+        // memories used to be embedded by whoever served the guild's model at the
+        // time; keep them with the one they have been using
+        for guild in state.guilds.values_mut() {
+            if guild.embedder.is_none() {
+                guild.embedder = Some(memory_provider(&state.models, guild));
+            }
+        }
+    }
+    if state.version < 4 {
+        // Jeeves' replies used to be told apart by username; they are the ones
+        // logged without a Discord id
+        for guild in state.guilds.values_mut() {
+            for utterance in guild.message_log.values_mut().flatten() {
+                utterance.from_bot = utterance.id.is_none();
+            }
+        }
+    }
+    if state.version < 5 {
+        // prices used to be kept on their own, and may have been changed from
+        // the list prices the registry starts with
+        for (model, price) in std::mem::take(&mut state.model_prices) {
+            let mut info = lookup_model(&state.models, &model);
+            info.price = price;
+            state.models.insert(model, info);
+        }
+    }
+    if state.version < 6 {
+        // channels' own schemas and parameters used to be kept in maps of their own
+        for guild in state.guilds.values_mut() {
+            for (channel_id, schema) in std::mem::take(&mut guild.channel_response_schemas) {
+                guild.configure_channel(&channel_id, |c| {
+                    c.response_schema.get_or_insert(schema);
+                });
+            }
+            for (channel_id, params) in std::mem::take(&mut guild.channel_gen_params) {
+                guild.configure_channel(&channel_id, |c| {
+                    if c.gen_params == GenerationParams::default() {
+                        c.gen_params = params;
+                    }
+                });
+            }
+        }
+    }
+    state.version = STATE_VERSION;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_old_prices_into_the_registry() {
+        let mut state = serde_json::from_str::<JeevesState>(
+            r#"{
+                "version": 4,
+                "guilds": {},
+                "model_prices": {
+                    "gpt-4": {"prompt_per_million": 25.0, "completion_per_million": 50.0},
+                    "my-finetune": {"prompt_per_million": 3.0, "completion_per_million": 6.0}
+                }
+            }"#,
+        )
+        .unwrap();
+        migrate_state(&mut state);
+        assert!(state.model_prices.is_empty());
+        assert_eq!(state.models["gpt-4"].price.prompt_per_million, 25.0);
+        assert_eq!(state.models["gpt-4"].context_length, 8192);
+        assert_eq!(
+            state.models["my-finetune"].price.completion_per_million,
+            6.0
+        );
+        assert!(!serde_json::to_string(&state)
+            .unwrap()
+            .contains("model_prices"));
+    }
+
+    #[test]
+    fn folds_old_channel_settings_into_channel_configs() {
+        let mut state = serde_json::from_str::<JeevesState>(
+            r#"{
+                "version": 5,
+                "guilds": {"g": {
+                    "id": "g",
+                    "our_channels": ["a", "b"],
+                    "message_log": {},
+                    "debug": false,
+                    "llm": "gpt-4",
+                    "system_prompt": "",
+                    "response_schema": "Pinged",
+                    "channel_response_schemas": {"a": "EveryMessage"},
+                    "channel_gen_params": {"a": {"temperature": 0.2}, "b": {"seed": 7}},
+                    "listen_to_roles": [],
+                    "ignore_roles": [],
+                    "listen_to_users": [],
+                    "ignore_users": []
+                }}
+            }"#,
+        )
+        .unwrap();
+        migrate_state(&mut state);
+        let guild = &state.guilds["g"];
+        assert!(guild.channel_response_schemas.is_empty());
+        assert!(guild.channel_gen_params.is_empty());
+        let a = &guild.channel_configs["a"];
+        assert!(matches!(
+            a.response_schema,
+            Some(BotResponseSchema::EveryMessage)
+        ));
+        assert_eq!(a.gen_params.temperature, Some(0.2));
+        assert_eq!(guild.channel_configs["b"].gen_params.seed, Some(7));
+        assert!(guild.channel_configs["b"].response_schema.is_none());
+    }
+
+    #[test]
+    fn marks_replies_logged_without_an_id_as_ours() {
+        let mut state = serde_json::from_str::<JeevesState>(
+            r#"{
+                "version": 3,
+                "guilds": {"g": {
+                    "id": "g",
+                    "our_channels": ["a"],
+                    "message_log": {"a": [
+                        {"id": "1", "username": "Jeeves", "content": "I am the real one"},
+                        {"id": null, "username": "Jeeves", "content": "Indeed, sir.", "in_reply_to": "1"}
+                    ]},
+                    "debug": false,
+                    "llm": "gpt-4",
+                    "system_prompt": "",
+                    "response_schema": "Pinged",
+                    "listen_to_roles": [],
+                    "ignore_roles": [],
+                    "listen_to_users": [],
+                    "ignore_users": []
+                }}
+            }"#,
+        )
+        .unwrap();
+        migrate_state(&mut state);
+        let log = &state.guilds["g"].message_log["a"];
+        assert!(!log[0].from_bot);
+        assert!(log[1].from_bot);
+    }
+}
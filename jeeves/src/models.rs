@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use crate::consts::*;
+use crate::empty_state;
+use crate::providers::Provider;
+use crate::types::*;
+use crate::usage::check_price;
+use discord_api::{
+    ApplicationCommandOption, ApplicationCommandOptionType, ApplicationCommandType, BotId,
+    CommandsCall, DiscordApiRequest, HttpApiCall, NewApplicationCommand,
+};
+use kinode_process_lib::{get_typed_state, Address, ProcessId, Request};
+
+/// The models Jeeves knows to begin with, at list prices in US dollars per
+/// million tokens. Local models are assumed to be multimodal, as llama.cpp
+/// ignores images it can't use.
+pub fn default_models() -> HashMap<String, ModelInfo> {
+    [
+        (
+            "gpt-3.5-turbo",
+            Provider::OpenAi,
+            16385,
+            false,
+            true,
+            0.5,
+            1.5,
+        ),
+        ("gpt-4", Provider::OpenAi, 8192, false, true, 30.0, 60.0),
+        (
+            "gpt-4-1106-preview",
+            Provider::OpenAi,
+            128000,
+            false,
+            true,
+            10.0,
+            30.0,
+        ),
+        (
+            "gpt-4-turbo-preview",
+            Provider::OpenAi,
+            128000,
+            false,
+            true,
+            10.0,
+            30.0,
+        ),
+        (
+            "gpt-4-vision-preview",
+            Provider::OpenAi,
+            128000,
+            true,
+            false,
+            10.0,
+            30.0,
+        ),
+        ("local", Provider::Lccp, 4096, true, false, 0.0, 0.0),
+    ]
+    .into_iter()
+    .map(
+        |(model, provider, context_length, vision, tools, prompt, completion)| {
+            (
+                model.to_string(),
+                ModelInfo {
+                    provider,
+                    context_length,
+                    vision,
+                    tools,
+                    price: ModelPrice {
+                        prompt_per_million: prompt,
+                        completion_per_million: completion,
+                    },
+                },
+            )
+        },
+    )
+    .collect()
+}
+
+/// What to make of a model that isn't in the registry, such as one removed
+/// since a reply to it was sent: a small model of no special talents, but
+/// reckoned as dear as the dearest its provider serves, so that quotas err
+/// on the side of caution.
+pub fn unregistered_model(models: &HashMap<String, ModelInfo>, model: &str) -> ModelInfo {
+    let provider = match model {
+        "local" => Provider::Lccp,
+        _ => Provider::OpenAi,
+    };
+    let dearest = |per_million: fn(&ModelPrice) -> f64| {
+        models
+            .values()
+            .filter(|info| info.provider == provider)
+            .map(|info| per_million(&info.price))
+            .fold(0.0, f64::max)
+    };
+    ModelInfo {
+        tools: provider == Provider::OpenAi,
+        context_length: 4096,
+        vision: false,
+        price: ModelPrice {
+            prompt_per_million: dearest(|p| p.prompt_per_million),
+            completion_per_million: dearest(|p| p.completion_per_million),
+        },
+        provider,
+    }
+}
+
+pub fn lookup_model(models: &HashMap<String, ModelInfo>, model: &str) -> ModelInfo {
+    models
+        .get(model)
+        .cloned()
+        .unwrap_or_else(|| unregistered_model(models, model))
+}
+
+/// Whether any guild would be left answering with `model` were it removed.
+pub fn model_in_use(state: &JeevesState, model: &str) -> bool {
+    state.guilds.values().any(|g| {
+        g.llm == model
+            || g.channel_configs
+                .values()
+                .any(|c| c.llm.as_deref() == Some(model))
+            || g.fallback_models.iter().any(|m| m == model)
+            || g.quotas.over_quota == OverQuota::Downgrade(model.to_string())
+    })
+}
+
+/// Change the registry as the node's owner has asked.
+pub fn apply_model_edit(state: &mut JeevesState, edit: ModelEdit) -> anyhow::Result<()> {
+    match edit {
+        ModelEdit::Add { model, info } => {
+            let model = model.trim().to_string();
+            if model.is_empty() {
+                return Err(anyhow::anyhow!("a model needs a name"));
+            }
+            if info.context_length == 0 {
+                return Err(anyhow::anyhow!("a model needs some context"));
+            }
+            check_price(&info.price)?;
+            state.models.insert(model, info);
+        }
+        ModelEdit::Remove { model } => {
+            // a guild left with a model we no longer know would be answered by guesswork
+            if model_in_use(state, &model) {
+                return Err(anyhow::anyhow!("{} is still relied upon by a guild", model));
+            }
+            if state.models.remove(&model).is_none() {
+                return Err(anyhow::anyhow!("no model {}", model));
+            }
+        }
+        ModelEdit::Price { model, price } => {
+            check_price(&price)?;
+            let Some(info) = state.models.get_mut(&model) else {
+                return Err(anyhow::anyhow!("no model {}", model));
+            };
+            info.price = price;
+        }
+    }
+    Ok(())
+}
+
+/// `/model`, whose description lists the registered models: the Discord API
+/// process can't offer them as choices.
+pub fn model_command(models: &HashMap<String, ModelInfo>) -> HttpApiCall {
+    let mut names = models.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    // Discord allows option descriptions of 100 characters at most
+    let mut description = "One of".to_string();
+    for (i, name) in names.iter().enumerate() {
+        let more = format!("{} {}", if i == 0 { ":" } else { "," }, name);
+        if description.len() + more.len() > 100 - ", ...".len() {
+            description.push_str(", ...");
+            break;
+        }
+        description.push_str(&more);
+    }
+    HttpApiCall::Commands(CommandsCall::CreateApplicationCommand {
+        application_id: BOT_APPLICATION_ID.trim().to_string(),
+        command: NewApplicationCommand {
+            name: "model".to_string(),
+            description: Some("Change the LLM that Jeeves will use".to_string()),
+            command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+            options: Some(vec![
+                ApplicationCommandOption {
+                    name: "model".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description,
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+                ApplicationCommandOption {
+                    name: "scope".to_string(),
+                    name_localizations: None,
+                    description_localizations: None,
+                    description: "Apply to this `channel` only, or the whole `guild` (default)"
+                        .to_string(),
+                    option_type: ApplicationCommandOptionType::String.as_u8(),
+                    required: Some(false),
+                },
+            ]),
+        },
+    })
+}
+
+/// Register `/model` afresh, as at start and whenever the registry changes.
+/// Registering a command by a name already taken replaces it.
+pub fn register_model_command(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    models: &HashMap<String, ModelInfo>,
+) -> anyhow::Result<()> {
+    Request::new()
+        .target((our.node.as_ref(), discord_api_id.clone()))
+        .body(serde_json::to_vec(&DiscordApiRequest::Http {
+            bot: bot.clone(),
+            call: model_command(models),
+        })?)
+        .expects_response(5)
+        .send()?;
+    Ok(())
+}
+
+/// The names of the registered models, in order.
+pub fn registered_models() -> Vec<String> {
+    let state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let mut models = state.models.keys().cloned().collect::<Vec<String>>();
+    models.sort();
+    models
+}
+
+pub fn provider_for_model(models: &HashMap<String, ModelInfo>, model: &str) -> Provider {
+    lookup_model(models, model).provider
+}
+
+/// How many tokens of prompt and reply together the model can take.
+pub fn context_length(models: &HashMap<String, ModelInfo>, model: &str) -> usize {
+    lookup_model(models, model).context_length
+}
+
+pub fn supports_vision(models: &HashMap<String, ModelInfo>, model: &str) -> bool {
+    lookup_model(models, model).vision
+}
+
+/// Only OpenAI does function calling, and not with every model.
+pub fn supports_tools(models: &HashMap<String, ModelInfo>, model: &str) -> bool {
+    let info = lookup_model(models, model);
+    info.tools && info.provider == Provider::OpenAi
+}
+
+pub fn parse_provider(name: &str) -> Option<Provider> {
+    match name.trim().to_lowercase().as_str() {
+        "openai" => Some(Provider::OpenAi),
+        "lccp" | "local" | "llama.cpp" => Some(Provider::Lccp),
+        _ => None,
+    }
+}
+
+pub fn provider_name(provider: &Provider) -> &'static str {
+    match provider {
+        Provider::OpenAi => "openai",
+        Provider::Lccp => "lccp",
+    }
+}
+
+pub fn describe_model(model: &str, info: &ModelInfo) -> String {
+    let mut talents = vec![];
+    if info.vision {
+        talents.push("vision");
+    }
+    if info.tools {
+        talents.push("tools");
+    }
+    format!(
+        "`{}`: {}, {} tokens of context{}, ${} prompt and ${} completion per million tokens",
+        model,
+        provider_name(&info.provider),
+        info.context_length,
+        if talents.is_empty() {
+            "".to_string()
+        } else {
+            format!(", {}", talents.join(" and "))
+        },
+        info.price.prompt_per_million,
+        info.price.completion_per_million
+    )
+}
+
+pub fn describe_models(models: &HashMap<String, ModelInfo>) -> String {
+    let mut names = models.keys().collect::<Vec<&String>>();
+    names.sort();
+    names
+        .iter()
+        .map(|m| describe_model(m, &models[*m]))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_models_are_priced_as_the_dearest() {
+        let models = default_models();
+        let info = lookup_model(&models, "gpt-5");
+        assert_eq!(info.price.prompt_per_million, 30.0);
+        assert_eq!(info.price.completion_per_million, 60.0);
+        let info = lookup_model(&models, "local");
+        assert_eq!(info.price.prompt_per_million, 0.0);
+    }
+
+    #[test]
+    fn edits_the_registry() {
+        let mut state = empty_state();
+        let info = ModelInfo {
+            provider: Provider::OpenAi,
+            context_length: 128000,
+            vision: true,
+            tools: true,
+            price: ModelPrice {
+                prompt_per_million: 5.0,
+                completion_per_million: 15.0,
+            },
+        };
+        apply_model_edit(
+            &mut state,
+            ModelEdit::Add {
+                model: "gpt-4o".to_string(),
+                info: info.clone(),
+            },
+        )
+        .unwrap();
+        assert_eq!(state.models["gpt-4o"], info);
+        let negative = ModelPrice {
+            prompt_per_million: -1.0,
+            completion_per_million: 1.0,
+        };
+        assert!(apply_model_edit(
+            &mut state,
+            ModelEdit::Price {
+                model: "gpt-4o".to_string(),
+                price: negative,
+            },
+        )
+        .is_err());
+        apply_model_edit(
+            &mut state,
+            ModelEdit::Remove {
+                model: "gpt-4o".to_string(),
+            },
+        )
+        .unwrap();
+        assert!(!state.models.contains_key("gpt-4o"));
+    }
+
+    #[test]
+    fn lists_models_in_the_command() {
+        let HttpApiCall::Commands(CommandsCall::CreateApplicationCommand { command, .. }) =
+            model_command(&default_models())
+        else {
+            panic!("not a command");
+        };
+        let description = &command.options.unwrap()[0].description;
+        assert!(description.len() <= 100);
+        assert!(description.starts_with("One of: gpt-3.5-turbo, gpt-4"));
+    }
+}
@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+
+use crate::consts::*;
+use crate::errors::JeevesError;
+use crate::llm_types::lccp;
+use crate::llm_types::openai;
+use crate::speakers::*;
+use crate::templates::*;
+use crate::tools::ToolUse;
+use crate::types::*;
+use crate::vision::MessageImages;
+use kinode_process_lib::ProcessId;
+
+/// The backends a guild's model choice can map to. Each is a process of the llm package.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Provider {
+    OpenAi,
+    /// llama.cpp, via the llm package's lccp process
+    Lccp,
+}
+
+pub fn provider_process(provider: &Provider) -> ProcessId {
+    match provider {
+        Provider::OpenAi => ProcessId::new(Some("openai"), "llm", "kinode"),
+        Provider::Lccp => ProcessId::new(Some("lccp"), "llm", "kinode"),
+    }
+}
+
+/// Only lccp can send a completion to us piece by piece.
+pub fn supports_streaming(provider: &Provider) -> bool {
+    provider == &Provider::Lccp
+}
+
+/// Build the request body for `provider` from (speaker, content) pairs.
+/// OpenAI is told who speaks by each message's name, falling back on writing
+/// it into the content with `attribution` when the name doesn't survive
+/// sanitizing. Raw-prompt providers render the conversation with `template`,
+/// and leave out `tool_use`. `images` go with the message at their index.
+pub fn build_chat_request(
+    provider: &Provider,
+    model: &str,
+    messages: &Vec<(Speaker, String)>,
+    images: Option<MessageImages>,
+    template: &ChatTemplate,
+    attribution: &str,
+    params: &GenerationParams,
+    tool_use: Option<ToolUse>,
+    stream: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let max_tokens = Some(params.max_tokens.unwrap_or(MAX_REPLY_TOKENS as u32) as i32);
+    match provider {
+        Provider::OpenAi => {
+            let mut new_messages = messages
+                .iter()
+                .map(|(speaker, content)| match speaker {
+                    Speaker::System | Speaker::Jeeves => openai::Message {
+                        role: if speaker == &Speaker::System {
+                            "system".to_string()
+                        } else {
+                            "assistant".to_string()
+                        },
+                        content: Some(content.clone().into()),
+                        ..Default::default()
+                    },
+                    Speaker::User(username) => {
+                        let name = sanitize_name(username);
+                        let content = if name.as_deref() == Some(username) {
+                            content.clone()
+                        } else {
+                            attribute(attribution, username, content)
+                        };
+                        openai::Message {
+                            role: "user".to_string(),
+                            content: Some(content.into()),
+                            name,
+                            ..Default::default()
+                        }
+                    }
+                })
+                .collect::<Vec<openai::Message>>();
+            if let Some(images) = images {
+                if let Some(message) = new_messages.get_mut(images.index) {
+                    let mut parts = vec![openai::ContentPart::Text {
+                        text: message
+                            .content
+                            .as_ref()
+                            .map(|c| c.text())
+                            .unwrap_or_default(),
+                    }];
+                    parts.extend(images.images.into_iter().map(|image| {
+                        openai::ContentPart::ImageUrl {
+                            image_url: openai::ImageUrl {
+                                url: format!("data:{};base64,{}", image.content_type, image.data),
+                            },
+                        }
+                    }));
+                    message.content = Some(openai::Content::Parts(parts));
+                }
+            }
+            let (tools, tool_choice) = match tool_use {
+                Some(tool_use) => {
+                    new_messages.extend(tool_use.exchange);
+                    (Some(tool_use.tools), tool_use.choice)
+                }
+                None => (None, None),
+            };
+            let chat_params = openai::ChatParams {
+                model: model.to_string(),
+                messages: new_messages,
+                max_tokens,
+                temperature: params.temperature,
+                top_p: params.top_p,
+                presence_penalty: params.presence_penalty,
+                frequency_penalty: params.frequency_penalty,
+                stop: params.stop.clone().map(openai::Stop::Array),
+                seed: params.seed.and_then(|s| i32::try_from(s).ok()),
+                logit_bias: params.logit_bias.clone(),
+                tools: tools.filter(|t| !t.is_empty()),
+                tool_choice,
+                ..Default::default()
+            };
+            let chat_request = openai::ChatRequest {
+                params: chat_params,
+                api_key: OPENAI_API_KEY.trim().to_string(),
+            };
+            Ok(openai::LLMRequest::Chat(chat_request).to_bytes())
+        }
+        Provider::Lccp => {
+            let mut stop = template.stop.clone();
+            stop.extend(params.stop.clone().unwrap_or_default());
+            // llama.cpp places each image where the prompt says [img-<id>]
+            let mut messages = messages.clone();
+            let mut image_data = None;
+            if let Some(images) = images {
+                if let Some(message) = messages.get_mut(images.index) {
+                    let data = images
+                        .images
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, image)| lccp::ImageData {
+                            data: image.data,
+                            id: i as u32 + 1,
+                        })
+                        .collect::<Vec<lccp::ImageData>>();
+                    for image in &data {
+                        message.1.push_str(&format!(" [img-{}]", image.id));
+                    }
+                    image_data = Some(data);
+                }
+            }
+            let chat_request = lccp::ChatRequest {
+                prompt: render_prompt(template, &messages),
+                n_predict: max_tokens,
+                temperature: params.temperature,
+                top_p: params.top_p,
+                presence_penalty: params.presence_penalty,
+                frequency_penalty: params.frequency_penalty,
+                stop: Some(stop),
+                seed: params.seed,
+                logit_bias: params.logit_bias.as_ref().map(|bias| {
+                    bias.iter()
+                        .filter_map(|(token, amount)| Some((token.parse().ok()?, *amount as f64)))
+                        .collect()
+                }),
+                stream: if stream { Some(true) } else { None },
+                image_data,
+                ..Default::default()
+            };
+            Ok(lccp::LLMRequest::Chat(chat_request).to_bytes())
+        }
+    }
+}
+
+pub fn parse_chat_response(provider: &Provider, body: &[u8]) -> anyhow::Result<String> {
+    match provider {
+        Provider::OpenAi => match openai::LLMResponse::parse(body)? {
+            openai::LLMResponse::Chat(chat) => Ok(chat.to_chat_response()?),
+            _ => Err(
+                JeevesError::Malformed("OpenAI sent the wrong kind of result".to_string()).into(),
+            ),
+        },
+        Provider::Lccp => match lccp::LLMResponse::parse(body)? {
+            lccp::LLMResponse::Chat(chat) => Ok(chat.content),
+            _ => {
+                Err(JeevesError::Malformed("lccp sent the wrong kind of result".to_string()).into())
+            }
+        },
+    }
+}
+
+/// What a chat completion cost in tokens, if the provider says.
+pub fn parse_chat_usage(provider: &Provider, body: &[u8]) -> Option<TokenUsage> {
+    match provider {
+        Provider::OpenAi => match openai::LLMResponse::parse(body).ok()? {
+            openai::LLMResponse::Chat(chat) => Some(TokenUsage {
+                prompt_tokens: chat.usage.prompt_tokens.max(0) as u64,
+                completion_tokens: chat.usage.completion_tokens.unwrap_or(0).max(0) as u64,
+            }),
+            _ => None,
+        },
+        Provider::Lccp => match lccp::LLMResponse::parse(body).ok()? {
+            lccp::LLMResponse::Chat(chat) => Some(TokenUsage {
+                prompt_tokens: chat.tokens_evaluated.max(0) as u64,
+                completion_tokens: chat.tokens_predicted.max(0) as u64,
+            }),
+            _ => None,
+        },
+    }
+}
+
+/// Embeddings from different models can't be compared, so each is stored with
+/// the name of what made it.
+pub fn embedder_name(provider: &Provider) -> String {
+    match provider {
+        Provider::OpenAi => format!("openai:{}", OPENAI_EMBEDDING_MODEL),
+        Provider::Lccp => "lccp".to_string(),
+    }
+}
+
+pub fn build_embedding_request(provider: &Provider, text: &str) -> Vec<u8> {
+    match provider {
+        Provider::OpenAi => openai::LLMRequest::Embedding(openai::EmbeddingRequest {
+            api_key: OPENAI_API_KEY.trim().to_string(),
+            params: openai::EmbeddingParams {
+                input: text.to_string(),
+                model: OPENAI_EMBEDDING_MODEL.to_string(),
+            },
+        })
+        .to_bytes(),
+        Provider::Lccp => lccp::LLMRequest::Embedding(lccp::EmbeddingRequest {
+            content: text.to_string(),
+            image_data: None,
+        })
+        .to_bytes(),
+    }
+}
+
+pub fn parse_embedding_response(provider: &Provider, body: &[u8]) -> anyhow::Result<Vec<f32>> {
+    match provider {
+        Provider::OpenAi => match openai::LLMResponse::parse(body)? {
+            openai::LLMResponse::Embedding(e) => Ok(e.embedding),
+            _ => Err(anyhow::Error::msg(
+                "Error embedding with OpenAI: wrong result",
+            )),
+        },
+        Provider::Lccp => match lccp::LLMResponse::parse(body)? {
+            lccp::LLMResponse::Embedding(e) => Ok(e.embedding),
+            _ => Err(anyhow::Error::msg(
+                "Error embedding with lccp: wrong result",
+            )),
+        },
+    }
+}
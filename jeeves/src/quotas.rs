@@ -0,0 +1,424 @@
+use chrono::{Datelike, Duration, Utc};
+
+use crate::empty_state;
+use crate::types::*;
+use kinode_process_lib::{get_typed_state, println, set_state, timer::set_timer};
+
+pub const QUOTA_PERIODS: [QuotaPeriod; 2] = [QuotaPeriod::Daily, QuotaPeriod::Monthly];
+
+/// Which day or month it is, in UTC.
+pub fn period_key(period: QuotaPeriod) -> String {
+    let now = Utc::now();
+    match period {
+        QuotaPeriod::Daily => now.format("%Y-%m-%d").to_string(),
+        QuotaPeriod::Monthly => now.format("%Y-%m").to_string(),
+    }
+}
+
+fn period_name(period: QuotaPeriod) -> &'static str {
+    match period {
+        QuotaPeriod::Daily => "daily",
+        QuotaPeriod::Monthly => "monthly",
+    }
+}
+
+fn describe_amount(unit: QuotaUnit, amount: f64) -> String {
+    match unit {
+        QuotaUnit::Tokens => format!("{} tokens", amount as u64),
+        QuotaUnit::Dollars => format!("${:.2}", amount),
+    }
+}
+
+fn spent(spend: &PeriodSpend, unit: QuotaUnit) -> f64 {
+    match unit {
+        QuotaUnit::Tokens => spend.tokens as f64,
+        QuotaUnit::Dollars => spend.dollars,
+    }
+}
+
+/// Start a fresh period wherever the day or month has turned, forgetting the old
+/// one's spending and warnings. Returns whether anything changed.
+pub fn roll_over_periods(settings: &mut QuotaSettings) -> bool {
+    let mut changed = false;
+    for period in QUOTA_PERIODS {
+        let key = period_key(period);
+        let spending = settings.spending.entry(period).or_default();
+        if spending.key != key {
+            *spending = PeriodSpending {
+                key,
+                ..Default::default()
+            };
+            let prefix = format!("{}:", period_name(period));
+            settings.warned.retain(|w| !w.starts_with(&prefix));
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Count spending against the guild's and the user's caps.
+pub fn charge_quotas(
+    settings: &mut QuotaSettings,
+    user_id: Option<&String>,
+    usage: &TokenUsage,
+    cost: f64,
+) {
+    roll_over_periods(settings);
+    let tokens = usage.prompt_tokens + usage.completion_tokens;
+    for spending in settings.spending.values_mut() {
+        spending.guild.tokens += tokens;
+        spending.guild.dollars += cost;
+        if let Some(user_id) = user_id {
+            let spend = spending.by_user.entry(user_id.clone()).or_default();
+            spend.tokens += tokens;
+            spend.dollars += cost;
+        }
+    }
+}
+
+/// What has been spent against `quota` this period, by the guild or by the user.
+fn spent_against(settings: &QuotaSettings, quota: &Quota, user_id: Option<&String>) -> f64 {
+    let Some(spending) = settings
+        .spending
+        .get(&quota.period)
+        .filter(|s| s.key == period_key(quota.period))
+    else {
+        return 0.0;
+    };
+    match quota.scope {
+        QuotaScope::Guild => spent(&spending.guild, quota.unit),
+        QuotaScope::User => user_id
+            .and_then(|u| spending.by_user.get(u))
+            .map(|s| spent(s, quota.unit))
+            .unwrap_or(0.0),
+    }
+}
+
+/// The first cap that the guild, or the user, has reached.
+pub fn reached_quota<'a>(guild: &'a GuildInfo, user_id: Option<&String>) -> Option<&'a Quota> {
+    first_reached(&guild.quotas, user_id)
+}
+
+fn first_reached<'a>(settings: &'a QuotaSettings, user_id: Option<&String>) -> Option<&'a Quota> {
+    settings
+        .quotas
+        .iter()
+        .find(|q| spent_against(settings, q, user_id) >= q.limit)
+}
+
+/// Hold a reply to the guild's caps: either it goes ahead, perhaps with a cheaper
+/// model, or we say why it can't.
+pub fn apply_quotas(guild: &GuildInfo, pending: PendingReply) -> Result<PendingReply, String> {
+    let Some(quota) = reached_quota(guild, pending.user_id.as_ref()) else {
+        return Ok(pending);
+    };
+    match &guild.quotas.over_quota {
+        OverQuota::Downgrade(model) => {
+            println!(
+                "jeeves: guild {} is over its {} cap, so answering with {}",
+                guild.id,
+                period_name(quota.period),
+                model
+            );
+            Ok(PendingReply {
+                downgrade_to: Some(model.clone()),
+                ..pending
+            })
+        }
+        OverQuota::Refuse => {
+            println!(
+                "jeeves: guild {} is over its {} cap",
+                guild.id,
+                period_name(quota.period)
+            );
+            Err(quota_refusal(quota))
+        }
+    }
+}
+
+pub fn quota_refusal(quota: &Quota) -> String {
+    let whose = match quota.scope {
+        QuotaScope::Guild => "the household's",
+        QuotaScope::User => "your",
+    };
+    let when = match quota.period {
+        QuotaPeriod::Daily => "tomorrow",
+        QuotaPeriod::Monthly => "at the turn of the month",
+    };
+    format!(
+        "I regret, sir, that {} {} allowance of {} is quite exhausted. I shall be at your service again {}.",
+        whose,
+        period_name(quota.period),
+        describe_amount(quota.unit, quota.limit),
+        when
+    )
+}
+
+/// Warnings for every threshold newly passed, each with the channel it should go
+/// to, marking them given. Spending in `channel_id` is what passed them.
+pub fn take_quota_warnings(guild_id: &String, channel_id: &String) -> Vec<(String, String)> {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(guild_id) else {
+        return vec![];
+    };
+    let warnings = new_warnings(&mut guild.quotas, channel_id);
+    if !warnings.is_empty() {
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+    }
+    warnings
+}
+
+fn new_warnings(settings: &mut QuotaSettings, channel_id: &String) -> Vec<(String, String)> {
+    let channel = settings.alert_channel.clone().unwrap_or(channel_id.clone());
+    let mut warnings = vec![];
+    for quota in settings.quotas.clone() {
+        if quota.limit <= 0.0 {
+            continue;
+        }
+        let spenders: Vec<Option<String>> = match quota.scope {
+            QuotaScope::Guild => vec![None],
+            QuotaScope::User => settings
+                .spending
+                .get(&quota.period)
+                .map(|s| s.by_user.keys().cloned().map(Some).collect())
+                .unwrap_or_default(),
+        };
+        for user_id in spenders {
+            let percent = spent_against(settings, &quota, user_id.as_ref()) / quota.limit * 100.0;
+            let Some(threshold) = settings
+                .warn_at
+                .iter()
+                .filter(|t| percent >= **t as f64)
+                .max()
+                .cloned()
+            else {
+                continue;
+            };
+            let key = format!(
+                "{}:{:?}:{:?}:{}:{}",
+                period_name(quota.period),
+                quota.scope,
+                quota.unit,
+                user_id.clone().unwrap_or_default(),
+                threshold
+            );
+            if settings.warned.contains(&key) {
+                continue;
+            }
+            settings.warned.push(key);
+            let who = match &user_id {
+                Some(user_id) => format!("<@{}> has", user_id),
+                None => "this guild has".to_string(),
+            };
+            warnings.push((
+                channel.clone(),
+                format!(
+                    "A word of caution, sir: {} spent {}% of the {} allowance of {}.",
+                    who,
+                    percent.min(999.0) as u32,
+                    period_name(quota.period),
+                    describe_amount(quota.unit, quota.limit)
+                ),
+            ));
+        }
+    }
+    warnings
+}
+
+/// Wake at the next midnight UTC, when the day, and perhaps the month, turns.
+pub fn start_quota_timer() {
+    let now = Utc::now();
+    let midnight = (now + Duration::days(1))
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .map(|m| m.and_utc());
+    let ms = midnight
+        .map(|m| (m - now).num_milliseconds().max(1000) as u64)
+        .unwrap_or(3_600_000);
+    set_timer(
+        ms,
+        Some(serde_json::to_vec(&ResponseContext::QuotaReset).unwrap_or(vec![])),
+    );
+}
+
+pub fn handle_quota_reset() {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let mut changed = false;
+    for guild in state.guilds.values_mut() {
+        changed |= roll_over_periods(&mut guild.quotas);
+    }
+    if changed {
+        println!(
+            "jeeves: quotas reset for {}{}",
+            period_key(QuotaPeriod::Daily),
+            if Utc::now().day() == 1 {
+                " and the new month"
+            } else {
+                ""
+            }
+        );
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+    }
+    start_quota_timer();
+}
+
+pub fn describe_quotas(settings: &QuotaSettings) -> String {
+    let caps = if settings.quotas.is_empty() {
+        "(no caps)".to_string()
+    } else {
+        settings
+            .quotas
+            .iter()
+            .map(|q| {
+                format!(
+                    "{} {} cap of {}",
+                    period_name(q.period),
+                    match q.scope {
+                        QuotaScope::Guild => "guild",
+                        QuotaScope::User => "per-user",
+                    },
+                    describe_amount(q.unit, q.limit)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+    let spent = QUOTA_PERIODS
+        .iter()
+        .map(|p| {
+            let spend = settings
+                .spending
+                .get(p)
+                .filter(|s| s.key == period_key(*p))
+                .map(|s| s.guild.clone())
+                .unwrap_or_default();
+            format!(
+                "{} so far: {} tokens, ${:.2}",
+                period_name(*p),
+                spend.tokens,
+                spend.dollars
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!(
+        "{}\nOnce reached: {}\nWarn at: {}\nWarnings go to: {}\n{}",
+        caps,
+        match &settings.over_quota {
+            OverQuota::Refuse => "refuse".to_string(),
+            OverQuota::Downgrade(model) => format!("answer with `{}`", model),
+        },
+        settings
+            .warn_at
+            .iter()
+            .map(|t| format!("{}%", t))
+            .collect::<Vec<String>>()
+            .join(", "),
+        settings
+            .alert_channel
+            .as_ref()
+            .map(|c| format!("<#{}>", c))
+            .unwrap_or("the channel where it was spent".to_string()),
+        spent
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(scope: QuotaScope, unit: QuotaUnit, limit: f64) -> Quota {
+        Quota {
+            scope,
+            period: QuotaPeriod::Daily,
+            unit,
+            limit,
+        }
+    }
+
+    fn tokens(prompt_tokens: u64, completion_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    #[test]
+    fn charges_guild_and_user_in_every_period() {
+        let mut settings = QuotaSettings::default();
+        let bertie = "bertie".to_string();
+        charge_quotas(&mut settings, Some(&bertie), &tokens(100, 50), 0.25);
+        charge_quotas(&mut settings, None, &tokens(10, 0), 0.5);
+        for period in QUOTA_PERIODS {
+            let spending = &settings.spending[&period];
+            assert_eq!(spending.key, period_key(period));
+            assert_eq!(spending.guild.tokens, 160);
+            assert_eq!(spending.guild.dollars, 0.75);
+            assert_eq!(spending.by_user[&bertie].tokens, 150);
+        }
+    }
+
+    #[test]
+    fn user_caps_hold_each_user_alone() {
+        let mut settings = QuotaSettings::default();
+        settings.quotas = vec![
+            quota(QuotaScope::Guild, QuotaUnit::Dollars, 10.0),
+            quota(QuotaScope::User, QuotaUnit::Tokens, 100.0),
+        ];
+        let (bertie, gussie) = ("bertie".to_string(), "gussie".to_string());
+        charge_quotas(&mut settings, Some(&bertie), &tokens(80, 20), 1.0);
+        assert_eq!(
+            first_reached(&settings, Some(&bertie)),
+            Some(&settings.quotas[1])
+        );
+        assert_eq!(first_reached(&settings, Some(&gussie)), None);
+        assert_eq!(first_reached(&settings, None), None);
+
+        charge_quotas(&mut settings, Some(&gussie), &tokens(1, 0), 9.0);
+        assert_eq!(
+            first_reached(&settings, Some(&gussie)),
+            Some(&settings.quotas[0])
+        );
+    }
+
+    #[test]
+    fn a_new_day_forgets_the_old_spending_and_warnings() {
+        let mut settings = QuotaSettings::default();
+        settings.quotas = vec![quota(QuotaScope::Guild, QuotaUnit::Tokens, 100.0)];
+        charge_quotas(&mut settings, None, &tokens(100, 0), 0.0);
+        settings.warned.push("daily:Guild:Tokens::80".to_string());
+        settings.spending.get_mut(&QuotaPeriod::Daily).unwrap().key = "1999-12-31".to_string();
+        assert_eq!(first_reached(&settings, None), None);
+
+        assert!(roll_over_periods(&mut settings));
+        assert_eq!(settings.spending[&QuotaPeriod::Daily].guild.tokens, 0);
+        assert_eq!(settings.spending[&QuotaPeriod::Monthly].guild.tokens, 100);
+        assert!(settings.warned.is_empty());
+        assert!(!roll_over_periods(&mut settings));
+    }
+
+    #[test]
+    fn warns_once_per_threshold() {
+        let mut settings = QuotaSettings::default();
+        settings.quotas = vec![quota(QuotaScope::Guild, QuotaUnit::Tokens, 100.0)];
+        settings.warn_at = vec![50, 90];
+        let channel = "general".to_string();
+
+        charge_quotas(&mut settings, None, &tokens(40, 0), 0.0);
+        assert!(new_warnings(&mut settings, &channel).is_empty());
+        charge_quotas(&mut settings, None, &tokens(20, 0), 0.0);
+        let warnings = new_warnings(&mut settings, &channel);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].0, channel);
+        assert!(warnings[0].1.contains("60%"));
+        assert!(new_warnings(&mut settings, &channel).is_empty());
+
+        settings.alert_channel = Some("alerts".to_string());
+        charge_quotas(&mut settings, None, &tokens(35, 0), 0.0);
+        let warnings = new_warnings(&mut settings, &channel);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].0, "alerts");
+    }
+}
@@ -0,0 +1,207 @@
+use chrono::Utc;
+
+use crate::types::*;
+
+pub enum RateLimited {
+    Guild,
+    Channel,
+    User,
+}
+
+fn bucket_key(scope: &str, id: &str) -> String {
+    format!("{}:{}", scope, id)
+}
+
+/// Take a token from each of the guild, channel and user buckets, or none of them
+/// if any bucket is empty, having first refilled them for the time gone by.
+/// Returns which bucket ran dry.
+pub fn try_take_token(
+    guild: &mut GuildInfo,
+    channel_id: &String,
+    user_id: &String,
+) -> Result<(), RateLimited> {
+    refill_buckets(guild, now_secs());
+    let limits = guild.rate_limits.clone();
+    let buckets = &mut guild.rate_buckets;
+
+    if let Some(limit) = &limits.guild {
+        if buckets.guild.unwrap_or(limit.capacity as f64) < 1.0 {
+            return Err(RateLimited::Guild);
+        }
+    }
+    if let Some(limit) = &limits.channel {
+        if *buckets
+            .channels
+            .get(channel_id)
+            .unwrap_or(&(limit.capacity as f64))
+            < 1.0
+        {
+            return Err(RateLimited::Channel);
+        }
+    }
+    if let Some(limit) = &limits.user {
+        if *buckets
+            .users
+            .get(user_id)
+            .unwrap_or(&(limit.capacity as f64))
+            < 1.0
+        {
+            return Err(RateLimited::User);
+        }
+    }
+
+    if let Some(limit) = &limits.guild {
+        buckets.guild = Some(buckets.guild.unwrap_or(limit.capacity as f64) - 1.0);
+    }
+    if let Some(limit) = &limits.channel {
+        *buckets
+            .channels
+            .entry(channel_id.clone())
+            .or_insert(limit.capacity as f64) -= 1.0;
+    }
+    if let Some(limit) = &limits.user {
+        *buckets
+            .users
+            .entry(user_id.clone())
+            .or_insert(limit.capacity as f64) -= 1.0;
+    }
+    Ok(())
+}
+
+/// Whether we should tell the user to wait, i.e. we haven't already for this bucket.
+pub fn should_warn(
+    guild: &mut GuildInfo,
+    limited: &RateLimited,
+    channel_id: &String,
+    user_id: &String,
+) -> bool {
+    let key = match limited {
+        RateLimited::Guild => bucket_key("guild", &guild.id),
+        RateLimited::Channel => bucket_key("channel", channel_id),
+        RateLimited::User => bucket_key("user", user_id),
+    };
+    if guild.rate_buckets.warned.contains(&key) {
+        return false;
+    }
+    guild.rate_buckets.warned.push(key);
+    true
+}
+
+pub fn rate_limited_message(limited: &RateLimited) -> String {
+    match limited {
+        RateLimited::Guild => "I beg your pardon, sir, but I am rather overcommitted in this establishment at present. Might I ask you to wait a moment?",
+        RateLimited::Channel => "I beg your pardon, sir, but this channel has kept me most occupied. Might I ask you to wait a moment?",
+        RateLimited::User => "If I may, sir, I should be glad to attend to you again presently. Might I ask you to wait a moment?",
+    }
+    .to_string()
+}
+
+/// Seconds since the epoch, which cooldowns and bucket refills are reckoned in.
+pub fn now_secs() -> f64 {
+    Utc::now().timestamp_millis() as f64 / 1000.0
+}
+
+/// Whether Jeeves is resting after a reply, in this channel or, for channels
+/// without a cooldown of their own, anywhere in the guild.
+pub fn on_cooldown(guild: &GuildInfo, channel_id: &String) -> bool {
+    let until = match guild
+        .channel_configs
+        .get(channel_id)
+        .and_then(|c| c.cooldown_secs)
+    {
+        Some(_) => guild
+            .channel_cooldowns
+            .get(channel_id)
+            .cloned()
+            .unwrap_or(0),
+        None => guild.cooldown_until,
+    };
+    until as f64 > now_secs()
+}
+
+/// Begin resting after a reply in `channel_id`.
+pub fn start_cooldown(guild: &mut GuildInfo, channel_id: &String) {
+    let now = now_secs() as u64;
+    guild.channel_cooldowns.retain(|_, until| *until > now);
+    match guild
+        .channel_configs
+        .get(channel_id)
+        .and_then(|c| c.cooldown_secs)
+    {
+        Some(0) => {}
+        Some(secs) => {
+            guild
+                .channel_cooldowns
+                .insert(channel_id.clone(), now + secs as u64);
+        }
+        None => guild.cooldown_until = now + guild.rate_limits.cooldown_secs as u64,
+    }
+}
+
+fn refill(tokens: &mut f64, limit: &RateLimit, elapsed_secs: f64) {
+    *tokens = (*tokens + limit.refill_per_minute * elapsed_secs / 60.0).min(limit.capacity as f64);
+}
+
+/// Refill every bucket for the time since it was last refilled. Full buckets are
+/// dropped, since a missing bucket is a full one.
+pub fn refill_buckets(guild: &mut GuildInfo, now: f64) {
+    let limits = guild.rate_limits.clone();
+    let buckets = &mut guild.rate_buckets;
+    let elapsed_secs = (now - buckets.refilled_at).max(0.0);
+    buckets.refilled_at = now;
+
+    match (&limits.guild, buckets.guild.as_mut()) {
+        (Some(limit), Some(tokens)) => {
+            refill(tokens, limit, elapsed_secs);
+            if *tokens >= limit.capacity as f64 {
+                buckets.guild = None;
+            }
+        }
+        (None, Some(_)) => buckets.guild = None,
+        _ => {}
+    }
+    for (limit, map) in [
+        (&limits.channel, &mut buckets.channels),
+        (&limits.user, &mut buckets.users),
+    ] {
+        match limit {
+            Some(limit) => {
+                map.values_mut()
+                    .for_each(|tokens| refill(tokens, limit, elapsed_secs));
+                map.retain(|_, tokens| *tokens < limit.capacity as f64);
+            }
+            None => map.clear(),
+        }
+    }
+
+    let guild_tokens = buckets.guild;
+    let channels = buckets.channels.clone();
+    let users = buckets.users.clone();
+    buckets.warned.retain(|key| {
+        let (scope, id) = key.split_once(':').unwrap_or(("", ""));
+        match scope {
+            "guild" => guild_tokens.map(|t| t < 1.0).unwrap_or(false),
+            "channel" => channels.get(id).map(|t| *t < 1.0).unwrap_or(false),
+            "user" => users.get(id).map(|t| *t < 1.0).unwrap_or(false),
+            _ => false,
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_with_time_up_to_capacity() {
+        let limit = RateLimit {
+            capacity: 5,
+            refill_per_minute: 6.0,
+        };
+        let mut tokens = 0.0;
+        refill(&mut tokens, &limit, 30.0);
+        assert_eq!(tokens, 3.0);
+        refill(&mut tokens, &limit, 3600.0);
+        assert_eq!(tokens, 5.0);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::models::*;
+use crate::providers::*;
+use crate::templates::*;
+use crate::types::*;
+
+/// How a speaker is written into a message's text when the provider can't be told
+/// who is speaking any other way.
+pub const DEFAULT_ATTRIBUTION: &str = "{name}: {content}";
+
+pub fn default_attribution() -> String {
+    DEFAULT_ATTRIBUTION.to_string()
+}
+
+/// OpenAI only takes names of up to 64 letters, digits, underscores and hyphens.
+/// Anything else becomes an underscore; a name with nothing left is no name.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let sanitized = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect::<String>();
+    if sanitized.chars().all(|c| c == '_') {
+        return None;
+    }
+    Some(sanitized)
+}
+
+/// Everyone but Jeeves who has spoken in the channel's log.
+pub fn channel_speakers(guild: &GuildInfo, channel_id: &String) -> Vec<String> {
+    let mut speakers = vec![];
+    for utterance in guild.message_log.get(channel_id).unwrap_or(&vec![]) {
+        if !utterance.from_bot && !speakers.contains(&utterance.username) {
+            speakers.push(utterance.username.clone());
+        }
+    }
+    speakers
+}
+
+pub fn attribute(format: &str, name: &str, content: &str) -> String {
+    format.replace("{name}", name).replace("{content}", content)
+}
+
+/// How `format`, with `{name}` and `{content}`, writes a speaker before what they
+/// say: "[{name}]: " in "[{name}]: {content}\n". Template markup before it on the
+/// line, such as "<s>[INST] ", is left out, as models write turns without it.
+pub fn speaker_tag(template: &ChatTemplate, format: &str) -> Option<String> {
+    let (before, _) = format.split_once("{content}")?;
+    let before = strip_control_tokens(template, &before.replace("{system}", ""));
+    let tag = before.rsplit('\n').next().unwrap_or("").trim_start();
+    if !tag.contains("{name}") {
+        return None;
+    }
+    Some(tag.to_string())
+}
+
+/// Each of `speakers` as the model saw them introduced: in the guild's attribution
+/// for OpenAI, which is told names apart, or else in the template's user turns.
+pub fn turn_tags(
+    models: &HashMap<String, ModelInfo>,
+    guild: &GuildInfo,
+    model: &str,
+    speakers: &[String],
+) -> Vec<String> {
+    let template = template_for_model(guild, model);
+    let format = match provider_for_model(models, model) {
+        Provider::OpenAi => &guild.attribution,
+        Provider::Lccp => &template.user,
+    };
+    let Some(tag) = speaker_tag(&template, format) else {
+        return vec![];
+    };
+    speakers
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| tag.replace("{name}", s))
+        .collect()
+}
+
+/// Models often start their reply by naming themselves, as they see others named,
+/// and sometimes carry on to write someone else's turn. Take off the first and cut
+/// off the second: only a line starting with one of `turn_tags` exactly is taken
+/// for someone else's turn.
+pub fn clean_reply(text: &str, turn_tags: &[String]) -> String {
+    // "[Jeeves]:", "Jeeves:", "**Jeeves**:", "<Jeeves> ", any number of times over
+    static OWN_TAG: OnceLock<Regex> = OnceLock::new();
+    let own_tag = OWN_TAG.get_or_init(|| {
+        Regex::new(r"(?i)^\s*(\*\*)?[\[<(]?\s*jeeves\s*[\]>)]?(\*\*)?\s*[:>]\s*").unwrap()
+    });
+    let mut text = text.trim().to_string();
+    while let Some(tag) = own_tag.find(&text) {
+        if tag.end() == 0 {
+            break;
+        }
+        text = text[tag.end()..].to_string();
+    }
+
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if line_start > 0
+            && turn_tags
+                .iter()
+                .any(|tag| trimmed.starts_with(tag.trim_end()))
+        {
+            text.truncate(line_start);
+            break;
+        }
+        line_start += line.len();
+    }
+    text.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::builtin_templates;
+
+    fn template(name: &str) -> ChatTemplate {
+        builtin_templates()
+            .into_iter()
+            .find(|t| t.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn finds_the_tag_in_each_format() {
+        let transcript = template("transcript");
+        assert_eq!(
+            speaker_tag(&transcript, &transcript.user),
+            Some("[{name}]: ".to_string())
+        );
+        for name in ["chatml", "llama2", "llama3", "mistral", "alpaca"] {
+            let t = template(name);
+            assert_eq!(
+                speaker_tag(&t, &t.user),
+                Some("{name}: ".to_string()),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            speaker_tag(&transcript, DEFAULT_ATTRIBUTION),
+            Some("{name}: ".to_string())
+        );
+        assert_eq!(speaker_tag(&transcript, "{content}"), None);
+    }
+
+    #[test]
+    fn takes_off_its_own_name() {
+        assert_eq!(
+            clean_reply("[Jeeves]: Very good, sir.", &[]),
+            "Very good, sir."
+        );
+        assert_eq!(clean_reply("**Jeeves**: Jeeves: Indeed.", &[]), "Indeed.");
+        assert_eq!(clean_reply("Jeeves is here.", &[]), "Jeeves is here.");
+    }
+
+    #[test]
+    fn cuts_off_turns_in_the_format_shown() {
+        let tags = vec!["[Bertie]: ".to_string()];
+        assert_eq!(
+            clean_reply("Tea is served.\n[Bertie]: Splendid!", &tags),
+            "Tea is served."
+        );
+        // written otherwise, it is Jeeves speaking of Bertie
+        assert_eq!(
+            clean_reply("Tea is served.\nBertie: a note for you.", &tags),
+            "Tea is served.\nBertie: a note for you."
+        );
+        // never the whole reply
+        assert_eq!(
+            clean_reply("[Bertie]: Splendid!", &tags),
+            "[Bertie]: Splendid!"
+        );
+    }
+}
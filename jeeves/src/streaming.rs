@@ -0,0 +1,236 @@
+use crate::consts::*;
+use crate::discord::*;
+use crate::llm_types::lccp;
+use crate::types::*;
+use discord_api::BotId;
+use kinode_process_lib::{println, timer::set_timer, Address, ProcessId};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Replies being streamed, keyed by the message being replied to. They live only
+/// in memory: a chunk arrives for every token, far too often to save state for,
+/// and a restart loses their completions anyway.
+fn streams() -> MutexGuard<'static, HashMap<String, StreamingReply>> {
+    static STREAMS: OnceLock<Mutex<HashMap<String, StreamingReply>>> = OnceLock::new();
+    STREAMS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Streams are keyed by the message being replied to.
+fn stream_key(pending: &PendingReply) -> String {
+    pending.message_id.clone()
+}
+
+/// Get ready to stream a reply if `wanted`, returning whether it may be.
+/// lccp's chunks say nothing of which request they answer, so only one reply
+/// streams at a time, and a retry or fallback of one isn't streamed: chunks
+/// of the abandoned request may yet arrive. It keeps any placeholder already
+/// posted, and fills it in when done.
+pub fn register_stream(pending: &PendingReply, wanted: bool) -> bool {
+    let mut streams = streams();
+    let key = stream_key(pending);
+    if let Some(stream) = streams.get_mut(&key) {
+        stream.pending = pending.clone();
+        stream.receiving = false;
+        return false;
+    }
+    if !wanted || streams.values().any(|s| s.receiving) {
+        return false;
+    }
+    streams.insert(
+        key,
+        StreamingReply {
+            pending: pending.clone(),
+            receiving: true,
+            content: String::new(),
+            placeholder_requested: false,
+            discord_message_id: None,
+            shown: String::new(),
+            final_text: None,
+        },
+    );
+    true
+}
+
+/// A chunk of a streamed completion, which can only be for the one reply
+/// being streamed.
+pub fn handle_stream_chunk(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let lccp::LLMResponse::ChatStreaming(chunk) = lccp::LLMResponse::parse(body)? else {
+        return Ok(());
+    };
+    let mut streams = streams();
+    let Some(stream) = streams
+        .values_mut()
+        .find(|s| s.receiving && s.final_text.is_none())
+    else {
+        println!("jeeves: got a stream chunk with no stream waiting for it");
+        return Ok(());
+    };
+    stream.content.push_str(&chunk.content);
+
+    if !stream.placeholder_requested && !stream.content.trim().is_empty() {
+        stream.placeholder_requested = true;
+        let key = stream_key(&stream.pending);
+        post_message_with_context(
+            format!("{} …", stream.content.trim()),
+            our,
+            bot,
+            discord_api_id,
+            stream.pending.channel_id.clone(),
+            &ResponseContext::PlaceholderPosted(key.clone()),
+        )?;
+        stream.shown = stream.content.clone();
+        set_timer(
+            STREAM_EDIT_INTERVAL_MS,
+            Some(serde_json::to_vec(&ResponseContext::StreamEditTick(key))?),
+        );
+    }
+    Ok(())
+}
+
+pub fn handle_placeholder_posted(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    key: String,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let mut streams = streams();
+    let Some(stream) = streams.get_mut(&key) else {
+        return Ok(());
+    };
+    let Some(message_id) = created_message_id(body) else {
+        println!("jeeves: no message id for stream placeholder");
+        // we can't edit what we can't find; the reply will be posted afresh when done
+        stream.placeholder_requested = false;
+        if let Some(text) = stream.final_text.clone() {
+            let stream = streams.remove(&key).unwrap();
+            drop(streams);
+            deliver_reply(text, our, bot, discord_api_id, stream.pending.channel_id)?;
+        }
+        return Ok(());
+    };
+    stream.discord_message_id = Some(message_id);
+
+    // the completion may have finished before Discord got back to us
+    if let Some(text) = stream.final_text.clone() {
+        let stream = streams.remove(&key).unwrap();
+        drop(streams);
+        return deliver_final_text(our, bot, discord_api_id, &stream, text);
+    }
+    Ok(())
+}
+
+/// Show the latest text, at most once per tick to stay within Discord's edit rate limits.
+pub fn handle_stream_edit_tick(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    key: String,
+) -> anyhow::Result<()> {
+    let mut streams = streams();
+    let Some(stream) = streams.get_mut(&key) else {
+        return Ok(());
+    };
+    if stream.final_text.is_some() {
+        return Ok(());
+    }
+    if let Some(message_id) = stream.discord_message_id.clone() {
+        if stream.content != stream.shown {
+            // while streaming, show only what fits in one message
+            let text = split_message(&format!("{} …", stream.content.trim())).remove(0);
+            edit_discord_message(
+                text,
+                our,
+                bot,
+                discord_api_id,
+                stream.pending.channel_id.clone(),
+                message_id,
+            )?;
+            stream.shown = stream.content.clone();
+        }
+    }
+    set_timer(
+        STREAM_EDIT_INTERVAL_MS,
+        Some(serde_json::to_vec(&ResponseContext::StreamEditTick(key))?),
+    );
+    Ok(())
+}
+
+/// Put the finished text in the stream's placeholder, if it has one.
+/// Returns false if there is nothing on Discord to finish, and the caller
+/// should post the text itself.
+pub fn finish_stream(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    pending: &PendingReply,
+    text: String,
+) -> anyhow::Result<bool> {
+    let mut streams = streams();
+    let key = stream_key(pending);
+    let Some(stream) = streams.get_mut(&key) else {
+        return Ok(false);
+    };
+    if !stream.placeholder_requested {
+        streams.remove(&key);
+        return Ok(false);
+    }
+    if stream.discord_message_id.is_none() {
+        stream.final_text = Some(text);
+        return Ok(true);
+    }
+    let stream = streams.remove(&key).unwrap();
+    drop(streams);
+    deliver_final_text(our, bot, discord_api_id, &stream, text)?;
+    Ok(true)
+}
+
+/// The first part of the text replaces the placeholder; any more follows as new messages.
+fn deliver_final_text(
+    our: &Address,
+    bot: &BotId,
+    discord_api_id: &ProcessId,
+    stream: &StreamingReply,
+    text: String,
+) -> anyhow::Result<()> {
+    let Some(message_id) = stream.discord_message_id.clone() else {
+        return Ok(());
+    };
+    let mut chunks = split_message(&text);
+    if let Err(e) = edit_discord_message(
+        chunks.remove(0),
+        our,
+        bot,
+        discord_api_id,
+        stream.pending.channel_id.clone(),
+        message_id,
+    ) {
+        // the placeholder stays as it was; post the whole reply afresh
+        println!("jeeves: {}", e);
+        return deliver_reply(
+            text,
+            our,
+            bot,
+            discord_api_id,
+            stream.pending.channel_id.clone(),
+        );
+    }
+    if !chunks.is_empty() {
+        deliver_reply(
+            chunks.concat(),
+            our,
+            bot,
+            discord_api_id,
+            stream.pending.channel_id.clone(),
+        )?;
+    }
+    Ok(())
+}
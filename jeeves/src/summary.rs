@@ -0,0 +1,229 @@
+use crate::completion::*;
+use crate::consts::*;
+use crate::context::*;
+use crate::empty_state;
+use crate::genparams::*;
+use crate::memory::*;
+use crate::models::*;
+use crate::providers::*;
+use crate::quotas::*;
+use crate::speakers::*;
+use crate::templates::*;
+use crate::types::*;
+use crate::usage::*;
+use kinode_process_lib::{get_typed_state, println, set_state, Address, Request};
+
+const SUMMARY_INSTRUCTIONS: &str = "You are keeping notes for Jeeves, the valet in a Discord channel. Summarize the conversation that follows for him: who was there, what was asked and settled, and anything he promised or should remember. Be brief and factual, and keep it under 200 words.";
+
+/// The channel's system prompt with what we remember of it from before its log.
+pub fn system_prompt_with_summary(guild: &GuildInfo, channel_id: &String) -> String {
+    let system_prompt = guild.system_prompt_for_channel(channel_id);
+    match guild.summaries.get(channel_id) {
+        Some(summary) if !summary.text.is_empty() => format!(
+            "{}\n\nSummary of the conversation in this channel before the messages that follow:\n{}",
+            system_prompt, summary.text
+        ),
+        _ => system_prompt.clone(),
+    }
+}
+
+/// Once a channel's log grows past the threshold, ask the guild's model to fold
+/// all but its newest messages into the channel's summary.
+pub fn maybe_summarize(guild_id: &String, channel_id: &String) -> anyhow::Result<()> {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(guild_id) else {
+        return Ok(());
+    };
+    let log = guild.message_log.get(channel_id).cloned().unwrap_or(vec![]);
+    if log.len() <= SUMMARIZE_AFTER_MESSAGES {
+        return Ok(());
+    }
+    let summary = guild.summaries.get(channel_id).cloned().unwrap_or_default();
+    if summary.in_progress {
+        return Ok(());
+    }
+
+    let older = &log[..log.len() - SUMMARY_KEEP_RECENT];
+    let mut instructions = SUMMARY_INSTRUCTIONS.to_string();
+    if !summary.text.is_empty() {
+        instructions.push_str(&format!(
+            "\n\nHere is the summary of what came before; fold it into yours:\n{}",
+            summary.text
+        ));
+    }
+    let mut messages = vec![(Speaker::System, instructions)];
+    for msg in older {
+        messages.push((msg.speaker(), msg.content.clone()));
+    }
+    messages.push((
+        Speaker::System,
+        "Now write the summary of the conversation above.".to_string(),
+    ));
+
+    // a summary is spending like any other, so it keeps to the guild's caps
+    let pending = PendingReply::new(
+        guild_id.clone(),
+        channel_id.clone(),
+        older[older.len() - 1].id.clone().unwrap_or_default(),
+        None,
+        false,
+    );
+    let pending = match apply_quotas(guild, pending) {
+        Ok(pending) => pending,
+        Err(_) => {
+            println!(
+                "jeeves: not summarizing channel {}: over its cap",
+                channel_id
+            );
+            return Ok(());
+        }
+    };
+    let Some(model) = reply_chain(guild, &pending).first().cloned() else {
+        return Ok(());
+    };
+    // the channel's settings, but a summary wants a steady hand
+    let params = GenerationParams {
+        temperature: Some(0.3),
+        ..effective_gen_params(guild, channel_id)
+    };
+    let budget = context_length(&state.models, &model).saturating_sub(reply_tokens(&params));
+    let messages = fit_to_budget(messages, budget);
+    let template = template_for_model(guild, &model);
+    let provider = provider_for_model(&state.models, &model);
+    let body = build_chat_request(
+        &provider,
+        &model,
+        &messages,
+        None,
+        &template,
+        &guild.attribution,
+        &params,
+        None,
+        false,
+    )?;
+
+    let request = SummaryRequest {
+        guild_id: guild_id.clone(),
+        channel_id: channel_id.clone(),
+        model,
+        through: older[older.len() - 1].clone(),
+    };
+    guild
+        .summaries
+        .entry(channel_id.clone())
+        .or_default()
+        .in_progress = true;
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    println!(
+        "jeeves: summarizing {} messages in channel {}",
+        older.len(),
+        channel_id
+    );
+    Request::new()
+        .target(Address::new("our", provider_process(&provider)))
+        .body(body)
+        .context(serde_json::to_vec(&ResponseContext::Summary(request))?)
+        .expects_response(LLM_TIMEOUT_SECS)
+        .send()
+}
+
+/// Jeeves' own replies have no message id, so match them on what they said and to whom.
+fn same_utterance(a: &Utterance, b: &Utterance) -> bool {
+    match (&a.id, &b.id) {
+        (Some(a_id), Some(b_id)) => a_id == b_id,
+        (None, None) => {
+            a.username == b.username && a.in_reply_to == b.in_reply_to && a.content == b.content
+        }
+        _ => false,
+    }
+}
+
+/// Store the new summary and drop the messages it covers from the log.
+pub fn handle_summary_response(request: SummaryRequest, body: &[u8]) -> anyhow::Result<()> {
+    record_usage(
+        &request.guild_id,
+        &request.channel_id,
+        None,
+        &request.model,
+        body,
+    );
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(&request.guild_id) else {
+        return Ok(());
+    };
+    // cleared while we were waiting
+    let Some(summary) = guild.summaries.get_mut(&request.channel_id) else {
+        return Ok(());
+    };
+    if !summary.in_progress {
+        return Ok(());
+    }
+    summary.in_progress = false;
+
+    let text = match parse_chat_response(&provider_for_model(&state.models, &request.model), body) {
+        Ok(text) => clean_reply(&text, &[]),
+        Err(e) => {
+            println!("jeeves: summary failed: {}", e);
+            set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+            return Ok(());
+        }
+    };
+    // if the last message summarized was edited or deleted meanwhile, we can't
+    // tell which messages the summary covers; better none than them twice over
+    let Some(log) = guild.message_log.get_mut(&request.channel_id) else {
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+        return Ok(());
+    };
+    let Some(index) = log.iter().position(|m| same_utterance(m, &request.through)) else {
+        println!(
+            "jeeves: the log of channel {} changed while it was summarized; dropping the summary",
+            request.channel_id
+        );
+        set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+        return Ok(());
+    };
+    let drained = log.drain(..=index).collect::<Vec<Utterance>>();
+    summary.text = text.clone();
+    println!(
+        "jeeves: new summary for channel {}: {}",
+        request.channel_id, text
+    );
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+
+    // what was summarized can still be recalled in detail
+    let Some(guild) = state.guilds.get(&request.guild_id) else {
+        return Ok(());
+    };
+    remember_utterances(&state.models, guild, &request.channel_id, drained)?;
+    remember_summary(&state.models, guild, &request.channel_id, &text)
+}
+
+/// Let the channel be summarized again after a summary request is lost.
+pub fn abandon_summary(request: SummaryRequest) {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(summary) = state
+        .guilds
+        .get_mut(&request.guild_id)
+        .and_then(|g| g.summaries.get_mut(&request.channel_id))
+    else {
+        return;
+    };
+    summary.in_progress = false;
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+}
+
+/// Summaries asked for before a restart will never arrive.
+pub fn clear_summaries_in_progress() {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    for guild in state.guilds.values_mut() {
+        for summary in guild.summaries.values_mut() {
+            summary.in_progress = false;
+        }
+    }
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+}
@@ -0,0 +1,332 @@
+use crate::calculator::*;
+use crate::clock::*;
+use crate::completion::*;
+use crate::consts::*;
+use crate::context::*;
+use crate::dice::*;
+use crate::llm_types::openai;
+use crate::types::*;
+use crate::units::*;
+use discord_api::{
+    ApplicationCommandOption, ApplicationCommandOptionType, ApplicationCommandType, CommandsCall,
+    HttpApiCall, NewApplicationCommand,
+};
+use kinode_process_lib::println;
+
+/// Where a tool is being used.
+pub struct ToolContext {
+    pub guild_id: String,
+    pub channel_id: String,
+}
+
+/// A function the model may call. `parameters` is the JSON schema of the
+/// arguments `run` is given; what `run` returns is shown to the model.
+pub struct ToolSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: fn() -> serde_json::Value,
+    pub run: fn(&ToolContext, serde_json::Value) -> anyhow::Result<String>,
+    /// The slash command that runs the tool directly, its options named after
+    /// the schema's properties.
+    pub command: Option<&'static str>,
+}
+
+/// Every tool Jeeves knows.
+pub fn tool_registry() -> Vec<ToolSpec> {
+    vec![
+        ToolSpec {
+            name: "calculator",
+            description: "Evaluate an arithmetic expression exactly. Supports + - * / % ^, parentheses, pi, e, and sqrt, abs, floor, ceil, round, sin, cos, tan, asin, acos, atan, exp, ln, log, min, max.",
+            parameters: || {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "expression": {
+                            "type": "string",
+                            "description": "The expression, e.g. (3 + 4.5) * 2^10 / 7"
+                        }
+                    },
+                    "required": ["expression"]
+                })
+            },
+            run: |_, args| evaluate(&string_arg(&args, "expression")?),
+            command: Some("calc"),
+        },
+        ToolSpec {
+            name: "roll_dice",
+            description: "Roll dice in standard notation and report each die and the total.",
+            parameters: || {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "dice": {
+                            "type": "string",
+                            "description": "Dice notation, e.g. 2d6+3, d20 or 1d8 + 2d4 - 1"
+                        }
+                    },
+                    "required": ["dice"]
+                })
+            },
+            run: |_, args| roll_dice(&string_arg(&args, "dice")?),
+            command: Some("roll"),
+        },
+        ToolSpec {
+            name: "current_time",
+            description: "Get the current date and time, in UTC or in a time zone.",
+            parameters: || {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "time_zone": {
+                            "type": "string",
+                            "description": "An IANA time zone, e.g. Europe/London or America/New_York; UTC if left out"
+                        }
+                    }
+                })
+            },
+            run: |_, args| current_time(args.get("time_zone").and_then(|v| v.as_str())),
+            command: Some("time"),
+        },
+        ToolSpec {
+            name: "convert_units",
+            description: "Convert a quantity between units of length, mass, volume, time, speed, data or temperature.",
+            parameters: || {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "value": {
+                            "type": "number",
+                            "description": "The quantity to convert"
+                        },
+                        "from": {
+                            "type": "string",
+                            "description": "The unit it is in, e.g. mi, kg, °F, cups"
+                        },
+                        "to": {
+                            "type": "string",
+                            "description": "The unit to convert to, e.g. km, lb, °C, ml"
+                        }
+                    },
+                    "required": ["value", "from", "to"]
+                })
+            },
+            run: |_, args| {
+                let Some(value) = args.get("value").and_then(|v| v.as_f64()) else {
+                    return Err(anyhow::anyhow!("missing number: value"));
+                };
+                convert_units(value, &string_arg(&args, "from")?, &string_arg(&args, "to")?)
+            },
+            command: Some("convert"),
+        },
+    ]
+}
+
+fn string_arg(args: &serde_json::Value, name: &str) -> anyhow::Result<String> {
+    args.get(name)
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+        .ok_or(anyhow::anyhow!("missing text: {}", name))
+}
+
+pub fn find_tool_by_command(command: &str) -> Option<ToolSpec> {
+    tool_registry()
+        .into_iter()
+        .find(|t| t.command == Some(command))
+}
+
+/// The slash command for a tool, with an option for each property of its schema.
+pub fn tool_slash_command(tool: &ToolSpec) -> Option<HttpApiCall> {
+    let command = tool.command?;
+    let parameters = (tool.parameters)();
+    let required = parameters["required"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let mut options = vec![];
+    for (name, property) in parameters["properties"].as_object()? {
+        let option_type = match property["type"].as_str() {
+            Some("number") => ApplicationCommandOptionType::Number,
+            Some("integer") => ApplicationCommandOptionType::Integer,
+            Some("boolean") => ApplicationCommandOptionType::Boolean,
+            _ => ApplicationCommandOptionType::String,
+        };
+        // Discord's limit for descriptions
+        let description = property["description"]
+            .as_str()
+            .unwrap_or(name)
+            .chars()
+            .take(100)
+            .collect::<String>();
+        options.push(ApplicationCommandOption {
+            name: name.clone(),
+            name_localizations: None,
+            description_localizations: None,
+            description,
+            option_type: option_type.as_u8(),
+            required: Some(required.contains(&serde_json::json!(name))),
+        });
+    }
+    // Discord wants required options first
+    options.sort_by_key(|o| !o.required.unwrap_or(false));
+    let description = tool
+        .description
+        .split(". ")
+        .next()
+        .unwrap_or(tool.name)
+        .chars()
+        .take(100)
+        .collect::<String>();
+    Some(HttpApiCall::Commands(
+        CommandsCall::CreateApplicationCommand {
+            application_id: BOT_APPLICATION_ID.trim().to_string(),
+            command: NewApplicationCommand {
+                name: command.to_string(),
+                description: Some(description),
+                command_type: Some(ApplicationCommandType::ChatInput.as_u8()),
+                options: Some(options),
+            },
+        },
+    ))
+}
+
+pub fn find_tool(name: &str) -> Option<ToolSpec> {
+    tool_registry().into_iter().find(|t| t.name == name)
+}
+
+/// Tools are on unless the guild has turned them off.
+pub fn enabled_tools(guild: &GuildInfo) -> Vec<ToolSpec> {
+    tool_registry()
+        .into_iter()
+        .filter(|t| !guild.disabled_tools.iter().any(|d| d == t.name))
+        .collect()
+}
+
+/// Function calling for one request: the tools on offer, and the calls made
+/// and their results so far.
+pub struct ToolUse {
+    pub tools: Vec<openai::Tool>,
+    pub choice: Option<openai::ToolChoice>,
+    pub exchange: Vec<openai::Message>,
+}
+
+/// What to offer the model in answering `pending`. Once the rounds are used up it
+/// still sees the tools, so earlier calls make sense, but may not call any more.
+pub fn tool_use_for(guild: &GuildInfo, pending: &PendingReply) -> Option<ToolUse> {
+    let tools = enabled_tools(guild)
+        .iter()
+        .map(|t| openai::Tool {
+            type_field: "function".to_string(),
+            function: openai::FunctionDefinition {
+                name: t.name.to_string(),
+                description: t.description.to_string(),
+                parameters: (t.parameters)(),
+            },
+        })
+        .collect::<Vec<openai::Tool>>();
+    if tools.is_empty() && pending.tool_exchange.is_empty() {
+        return None;
+    }
+    Some(ToolUse {
+        tools,
+        choice: if pending.tool_rounds >= MAX_TOOL_ROUNDS {
+            Some(openai::ToolChoice::Mode(openai::ToolChoiceMode::None))
+        } else {
+            None
+        },
+        exchange: pending.tool_exchange.clone(),
+    })
+}
+
+/// What the tool definitions and the exchange so far add to the prompt.
+pub fn estimate_tool_tokens(tool_use: &ToolUse) -> usize {
+    let tools = serde_json::to_string(&tool_use.tools).unwrap_or_default();
+    estimate_tokens(&tools)
+        + tool_use
+            .exchange
+            .iter()
+            .map(|m| estimate_tokens(&serde_json::to_string(m).unwrap_or_default()))
+            .sum::<usize>()
+}
+
+/// The assistant message, if the model answered with tool calls rather than text.
+pub fn tool_call_message(body: &[u8]) -> Option<openai::Message> {
+    let Ok(openai::LLMResponse::Chat(chat)) = openai::LLMResponse::parse(body) else {
+        return None;
+    };
+    let message = chat.choices.into_iter().next()?.message;
+    match &message.tool_calls {
+        Some(calls) if !calls.is_empty() => Some(message),
+        _ => None,
+    }
+}
+
+fn run_tool_call(guild: &GuildInfo, context: &ToolContext, call: &openai::ToolCall) -> String {
+    let Some(tool) = find_tool(&call.function.name) else {
+        return format!("Error: there is no tool called {}", call.function.name);
+    };
+    if guild.disabled_tools.iter().any(|d| d == tool.name) {
+        return format!("Error: {} is disabled in this guild", tool.name);
+    }
+    let args = match serde_json::from_str::<serde_json::Value>(&call.function.arguments) {
+        Ok(args) => args,
+        Err(e) => return format!("Error: the arguments are not valid JSON: {}", e),
+    };
+    match (tool.run)(context, args) {
+        Ok(result) => result,
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Run the calls the model asked for and ask it again, with their results.
+pub fn handle_tool_calls(
+    guild: &GuildInfo,
+    pending: PendingReply,
+    message: openai::Message,
+) -> anyhow::Result<()> {
+    let mut exchange = pending.tool_exchange.clone();
+    let calls = message.tool_calls.clone().unwrap_or_default();
+    exchange.push(message);
+    let context = ToolContext {
+        guild_id: pending.guild_id.clone(),
+        channel_id: pending.channel_id.clone(),
+    };
+    for call in &calls {
+        let result = run_tool_call(guild, &context, call);
+        println!(
+            "jeeves: tool {}({}) -> {}",
+            call.function.name, call.function.arguments, result
+        );
+        exchange.push(openai::Message {
+            role: "tool".to_string(),
+            content: Some(result.into()),
+            tool_call_id: Some(call.id.clone()),
+            ..Default::default()
+        });
+    }
+    request_completion_for_guild_channel(PendingReply {
+        tool_exchange: exchange,
+        tool_rounds: pending.tool_rounds + 1,
+        attempt: 0,
+        ..pending
+    })
+}
+
+pub fn describe_tools(guild: &GuildInfo) -> String {
+    let tools = tool_registry();
+    if tools.is_empty() {
+        return "(none)".to_string();
+    }
+    tools
+        .iter()
+        .map(|t| {
+            let on = !guild.disabled_tools.iter().any(|d| d == t.name);
+            format!(
+                "`{}` ({}): {}",
+                t.name,
+                if on { "on" } else { "off" },
+                t.description
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
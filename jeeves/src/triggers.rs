@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use regex::Regex;
+
+use crate::types::*;
+use kinode_process_lib::println;
+
+/// The schema in force for a channel: its own override if one is set, else the guild's.
+pub fn response_schema_for_channel<'a>(
+    guild: &'a GuildInfo,
+    channel_id: &String,
+) -> &'a BotResponseSchema {
+    guild
+        .channel_configs
+        .get(channel_id)
+        .and_then(|c| c.response_schema.as_ref())
+        .unwrap_or(&guild.response_schema)
+}
+
+pub fn should_respond(schema: &BotResponseSchema, content: &str, pinged: bool) -> bool {
+    match schema {
+        BotResponseSchema::EveryMessage => true,
+        BotResponseSchema::Pinged => pinged,
+        BotResponseSchema::WordOrPhrase(phrases) => {
+            pinged || phrases.iter().any(|p| phrase_matches(p, content))
+        }
+    }
+}
+
+pub fn phrase_matches(trigger: &TriggerPhrase, content: &str) -> bool {
+    match cached_trigger_regex(trigger) {
+        Ok(re) => re.is_match(content),
+        Err(e) => {
+            println!("jeeves: bad trigger phrase {:?}: {}", trigger.phrase, e);
+            false
+        }
+    }
+}
+
+/// Triggers are checked against every message, so each is compiled only the
+/// first time it is seen.
+fn cached_trigger_regex(trigger: &TriggerPhrase) -> Result<Regex, regex::Error> {
+    static COMPILED: OnceLock<Mutex<HashMap<String, Regex>>> = OnceLock::new();
+    let pattern = trigger_pattern(trigger);
+    let mut compiled = COMPILED
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(re) = compiled.get(&pattern) {
+        return Ok(re.clone());
+    }
+    let re = Regex::new(&pattern)?;
+    compiled.insert(pattern, re.clone());
+    Ok(re)
+}
+
+/// Every trigger, plain or not, is matched by compiling it down to a regex.
+pub fn trigger_regex(trigger: &TriggerPhrase) -> Result<Regex, regex::Error> {
+    Regex::new(&trigger_pattern(trigger))
+}
+
+fn trigger_pattern(trigger: &TriggerPhrase) -> String {
+    let mut pattern = if trigger.regex {
+        trigger.phrase.clone()
+    } else {
+        regex::escape(&trigger.phrase)
+    };
+    if trigger.whole_word {
+        pattern = format!(r"\b(?:{})\b", pattern);
+    }
+    if !trigger.case_sensitive {
+        pattern = format!("(?i){}", pattern);
+    }
+    pattern
+}
+
+/// Parses the comma-separated phrase list given to `/respond`.
+/// A phrase wrapped in slashes, like `/butler?s?/`, is treated as a regex.
+pub fn parse_trigger_phrases(
+    phrases: &str,
+    case_sensitive: bool,
+    whole_word: bool,
+) -> anyhow::Result<Vec<TriggerPhrase>> {
+    let mut triggers = vec![];
+    for raw in phrases.split(',') {
+        let raw = raw.trim();
+        if raw.is_empty() {
+            continue;
+        }
+        let is_regex = raw.len() > 2 && raw.starts_with('/') && raw.ends_with('/');
+        let trigger = TriggerPhrase {
+            phrase: if is_regex {
+                raw[1..raw.len() - 1].to_string()
+            } else {
+                raw.to_string()
+            },
+            case_sensitive,
+            whole_word,
+            regex: is_regex,
+        };
+        if let Err(e) = trigger_regex(&trigger) {
+            return Err(anyhow::anyhow!("invalid trigger {}: {}", raw, e));
+        }
+        triggers.push(trigger);
+    }
+    if triggers.is_empty() {
+        return Err(anyhow::anyhow!("no trigger phrases given"));
+    }
+    Ok(triggers)
+}
+
+pub fn describe_response_schema(schema: &BotResponseSchema) -> String {
+    match schema {
+        BotResponseSchema::Pinged => "when pinged".to_string(),
+        BotResponseSchema::EveryMessage => "every message".to_string(),
+        BotResponseSchema::WordOrPhrase(phrases) => format!(
+            "when pinged or on: {}",
+            phrases
+                .iter()
+                .map(|p| if p.regex {
+                    format!("/{}/", p.phrase)
+                } else {
+                    format!("\"{}\"", p.phrase)
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggers(phrases: &str, case_sensitive: bool, whole_word: bool) -> BotResponseSchema {
+        BotResponseSchema::WordOrPhrase(
+            parse_trigger_phrases(phrases, case_sensitive, whole_word).unwrap(),
+        )
+    }
+
+    #[test]
+    fn pinged_only_answers_pings() {
+        assert!(should_respond(&BotResponseSchema::Pinged, "hello", true));
+        assert!(!should_respond(&BotResponseSchema::Pinged, "hello", false));
+        assert!(should_respond(
+            &BotResponseSchema::EveryMessage,
+            "hello",
+            false
+        ));
+    }
+
+    #[test]
+    fn phrases_match_whole_words_ignoring_case() {
+        let schema = triggers("butler, good show", false, true);
+        assert!(should_respond(&schema, "Where is the Butler?", false));
+        assert!(should_respond(&schema, "good SHOW, old bean", false));
+        assert!(!should_respond(&schema, "butlers everywhere", false));
+        assert!(should_respond(&schema, "nothing to see", true));
+    }
+
+    #[test]
+    fn phrases_can_be_case_sensitive_or_partial() {
+        let schema = triggers("Tea", true, false);
+        assert!(should_respond(&schema, "Teatime", false));
+        assert!(!should_respond(&schema, "tea", false));
+    }
+
+    #[test]
+    fn slashed_phrases_are_regexes() {
+        let schema = triggers("/butlers?/", false, true);
+        assert!(should_respond(&schema, "two butlers", false));
+        assert!(!should_respond(&schema, "butlering", false));
+    }
+
+    #[test]
+    fn bad_phrase_lists_are_refused() {
+        assert!(parse_trigger_phrases(" , ", false, true).is_err());
+        assert!(parse_trigger_phrases("/(unclosed/", false, true).is_err());
+    }
+}
@@ -2,13 +2,28 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::llm_types::openai;
+use crate::migrations::STATE_VERSION;
+use crate::models::default_models;
+use crate::providers::Provider;
+use crate::speakers::default_attribution;
+use crate::templates::ChatTemplate;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum BotResponseSchema {
     Pinged,
-    WordOrPhrase(String),
+    WordOrPhrase(Vec<TriggerPhrase>),
     EveryMessage,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TriggerPhrase {
+    pub phrase: String,
+    pub case_sensitive: bool,
+    pub whole_word: bool,
+    pub regex: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum BotAdminRequest {
     JoinGuild(String),
@@ -23,11 +38,482 @@ pub enum BotAdminRequest {
     DefineChannels(Vec<String>),
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub refill_per_minute: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimits {
+    pub guild: Option<RateLimit>,
+    pub channel: Option<RateLimit>,
+    pub user: Option<RateLimit>,
+    /// Seconds Jeeves stays quiet in a guild after each reply.
+    pub cooldown_secs: u32,
+}
+
+/// Tokens left in each bucket. A bucket that is missing is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateBuckets {
+    pub guild: Option<f64>,
+    pub channels: HashMap<String, f64>,
+    pub users: HashMap<String, f64>,
+    /// When the buckets were last refilled, in seconds since the epoch.
+    #[serde(default)]
+    pub refilled_at: f64,
+    /// Buckets we have already sent a "please wait" for, so we only say it once.
+    pub warned: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureSettings {
+    /// Record every message in our channels, not just the ones Jeeves replies to.
+    pub enabled: bool,
+    /// How many utterances to keep per channel.
+    pub window: usize,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        // keeping what people say to each other is for each guild to opt into
+        CaptureSettings {
+            enabled: false,
+            window: 100,
+        }
+    }
+}
+
+/// Sampling settings for completions. Unset fields fall back to the guild's
+/// settings, then to Jeeves' defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GenerationParams {
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<i64>,
+    /// Bias by token ID.
+    pub logit_bias: Option<HashMap<String, i32>>,
+}
+
+/// Carried in the context of requests we expect responses to,
+/// so the Response branch knows what each response is for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ResponseContext {
+    /// A timer for the start of the next day, when quota periods roll over.
+    QuotaReset,
+    Completion(PendingReply),
+    /// A timer to try a failed completion again.
+    RetryCompletion(PendingReply),
+    /// Discord created the placeholder message for the stream with this key.
+    PlaceholderPosted(String),
+    /// A timer to push the stream with this key's latest text to Discord.
+    StreamEditTick(String),
+    Summary(SummaryRequest),
+    /// An embedding of a memory, to be added to its guild's index.
+    MemoryEmbedding(MemorySnippet),
+    /// An embedding of the message being answered, to recall memories with.
+    RecallEmbedding(PendingReply, Provider),
+    /// An embedding of a passage of a knowledge-base document.
+    KnowledgeEmbedding(KnowledgePassage),
+    /// Discord's answer to posting part of a finished reply.
+    Delivery(ReplyDelivery),
+    /// A timer to post part of a finished reply again.
+    RetryDelivery(ReplyDelivery),
+    /// A document attached to `/knowledge add` has been downloaded, or not.
+    DocumentFetched(DocumentFetch),
+    /// One of the images of the message being answered has been downloaded, or not.
+    ImageFetched {
+        message_id: String,
+        index: usize,
+    },
+}
+
+/// Tokens spent on one request to a model.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// In US dollars, at the prices of the time.
+    pub cost: f64,
+}
+
+/// A guild's spending, in total and broken down every way we care about.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageLedger {
+    pub total: UsageTotals,
+    pub by_channel: HashMap<String, UsageTotals>,
+    /// By Discord user ID; summaries are charged to no one.
+    pub by_user: HashMap<String, UsageTotals>,
+    pub by_model: HashMap<String, UsageTotals>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaUnit {
+    Tokens,
+    Dollars,
+}
+
+/// Whether a cap is on the guild as a whole or on each of its users.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaScope {
+    Guild,
+    User,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Quota {
+    pub scope: QuotaScope,
+    pub period: QuotaPeriod,
+    pub unit: QuotaUnit,
+    pub limit: f64,
+}
+
+/// What to do once a cap is reached.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum OverQuota {
+    #[default]
+    Refuse,
+    /// Answer with this, presumably cheaper, model instead.
+    Downgrade(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PeriodSpend {
+    pub tokens: u64,
+    pub dollars: f64,
+}
+
+/// Spending in one day or month.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PeriodSpending {
+    /// Which day or month, like `2024-03-01` or `2024-03`.
+    pub key: String,
+    pub guild: PeriodSpend,
+    pub by_user: HashMap<String, PeriodSpend>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuotaSettings {
+    pub quotas: Vec<Quota>,
+    #[serde(default)]
+    pub over_quota: OverQuota,
+    /// Percentages of a cap at which to warn.
+    #[serde(default)]
+    pub warn_at: Vec<u32>,
+    /// Where warnings go; by default, wherever the spending happened.
+    #[serde(default)]
+    pub alert_channel: Option<String>,
+    #[serde(default)]
+    pub spending: HashMap<QuotaPeriod, PeriodSpending>,
+    /// Warnings already given this period, so each is given once.
+    #[serde(default)]
+    pub warned: Vec<String>,
+}
+
+impl Default for QuotaSettings {
+    fn default() -> Self {
+        QuotaSettings {
+            quotas: vec![],
+            over_quota: OverQuota::Refuse,
+            warn_at: vec![80],
+            alert_channel: None,
+            spending: HashMap::new(),
+            warned: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelPrice {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+/// What we know of a model Jeeves can talk to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub provider: Provider,
+    /// How many tokens of prompt and reply together it can take.
+    pub context_length: usize,
+    /// Whether it can look at images.
+    pub vision: bool,
+    /// Whether it can call tools.
+    pub tools: bool,
+    pub price: ModelPrice,
+}
+
+/// A passage of an uploaded document, being embedded for the guild's knowledge base.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnowledgePassage {
+    pub guild_id: String,
+    pub document: String,
+    /// Which upload of the document this is from; an upload replaced or
+    /// removed before its passages are all back is not filed.
+    pub upload_id: u64,
+    pub text: String,
+    pub provider: Provider,
+}
+
+/// A passage of a knowledge-base document consulted for a reply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reference {
+    pub document: String,
+    pub text: String,
+}
+
+/// A change to the model registry, posted by the UI to `/models`. Only the
+/// node's owner can reach it, as the registry is shared by every guild.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ModelEdit {
+    /// Add a model, or replace what we know of one.
+    Add {
+        model: String,
+        info: ModelInfo,
+    },
+    Remove {
+        model: String,
+    },
+    Price {
+        model: String,
+        price: ModelPrice,
+    },
+}
+
+/// A document posted by the UI to `/knowledge`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentUpload {
+    pub guild_id: String,
+    pub name: String,
+    pub text: String,
+}
+
+/// Some conversation that has left a channel's log, being embedded to be remembered.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemorySnippet {
+    pub guild_id: String,
+    pub channel_id: String,
+    pub text: String,
+    pub provider: Provider,
+}
+
+/// A summary of a channel's older messages we have asked the LLM for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SummaryRequest {
+    pub guild_id: String,
+    pub channel_id: String,
+    pub model: String,
+    /// The newest utterance summarized; it and everything before it leave the log
+    /// once the summary is in.
+    pub through: Utterance,
+}
+
+/// What Jeeves remembers of a channel's conversation from before its log.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChannelSummary {
+    pub text: String,
+    /// Whether a new summary has been asked for and not yet arrived.
+    pub in_progress: bool,
+}
+
+/// A reply being streamed into a Discord message as the model writes it.
+#[derive(Debug, Clone)]
+pub struct StreamingReply {
+    pub pending: PendingReply,
+    /// Whether lccp is sending us this reply piece by piece. A retry or fallback
+    /// of it isn't streamed, and only fills in the placeholder when done.
+    pub receiving: bool,
+    pub content: String,
+    /// Whether we have asked Discord for the placeholder message yet.
+    pub placeholder_requested: bool,
+    pub discord_message_id: Option<String>,
+    /// The text the Discord message shows as of our last edit.
+    pub shown: String,
+    /// The finished reply, held until the placeholder exists to put it in.
+    pub final_text: Option<String>,
+}
+
+/// Part of a finished reply on its way to a channel. The text is kept so that
+/// a failed post can be tried again without asking the model afresh.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplyDelivery {
+    pub channel_id: String,
+    pub content: String,
+    /// How many retries have already been made.
+    pub attempt: u32,
+}
+
+/// A document being downloaded for a guild's knowledge base; the outcome is
+/// reported to the channel the command was given in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentFetch {
+    pub guild_id: String,
+    pub channel_id: String,
+    pub name: String,
+}
+
+/// A reply we have asked the LLM for and not yet posted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingReply {
+    pub guild_id: String,
+    pub channel_id: String,
+    /// The message being replied to.
+    pub message_id: String,
+    /// Whether this rewrites an earlier reply to `message_id` after it was edited.
+    pub regenerate: bool,
+    /// The model asked; filled in when the request is sent.
+    #[serde(default)]
+    pub model: String,
+    /// Position in the guild's model chain: 0 is its chosen model, then its fallbacks.
+    #[serde(default)]
+    pub chain_index: usize,
+    /// Retries of the current model so far.
+    #[serde(default)]
+    pub attempt: u32,
+    /// How many times the conversation has been halved to fit the current model.
+    #[serde(default)]
+    pub context_shrinks: u32,
+    /// Tool calls the model has made in this reply, and their results.
+    #[serde(default)]
+    pub tool_exchange: Vec<openai::Message>,
+    #[serde(default)]
+    pub tool_rounds: u32,
+    /// Older conversation recalled for this reply; None until we have looked.
+    #[serde(default)]
+    pub memories: Option<Vec<String>>,
+    /// Knowledge-base passages found alongside the memories.
+    #[serde(default)]
+    pub references: Vec<Reference>,
+    /// Who wrote the message being replied to, to charge the reply to.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// The model to answer with instead of the guild's, as its spending is capped.
+    #[serde(default)]
+    pub downgrade_to: Option<String>,
+}
+
+impl PendingReply {
+    pub fn new(
+        guild_id: String,
+        channel_id: String,
+        message_id: String,
+        user_id: Option<String>,
+        regenerate: bool,
+    ) -> Self {
+        PendingReply {
+            guild_id,
+            channel_id,
+            message_id,
+            user_id,
+            downgrade_to: None,
+            regenerate,
+            model: String::new(),
+            chain_index: 0,
+            attempt: 0,
+            context_shrinks: 0,
+            tool_exchange: vec![],
+            tool_rounds: 0,
+            memories: None,
+            references: vec![],
+        }
+    }
+
+    pub fn with_model(self, model: String) -> Self {
+        PendingReply { model, ..self }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Utterance {
     pub id: Option<String>,
     pub username: String,
     pub content: String,
+    /// For Jeeves' replies, the id of the message that prompted it.
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    /// For Jeeves' replies, the model that actually answered.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Images attached to the message.
+    #[serde(default)]
+    pub images: Vec<ImageAttachment>,
+    /// Whether this is one of Jeeves' replies. Anyone may call themselves
+    /// "Jeeves", so this, not the username, says who spoke.
+    #[serde(default)]
+    pub from_bot: bool,
+}
+
+impl Utterance {
+    pub fn speaker(&self) -> Speaker {
+        if self.from_bot {
+            Speaker::Jeeves
+        } else {
+            Speaker::User(self.username.clone())
+        }
+    }
+}
+
+/// Who says a message of a prompt, which decides its role there.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Speaker {
+    System,
+    Jeeves,
+    User(String),
+}
+
+/// An image attached to a Discord message. Only where it is is kept; it is fetched
+/// when a model that can see it answers the message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageAttachment {
+    pub url: String,
+    pub filename: String,
+    pub content_type: String,
+}
+
+/// Settings a channel keeps apart from its guild's. Whatever is left unset is
+/// the guild's.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChannelConfig {
+    #[serde(default)]
+    pub llm: Option<String>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub response_schema: Option<BotResponseSchema>,
+    /// Laid over the guild's `gen_params`.
+    #[serde(default)]
+    pub gen_params: GenerationParams,
+    #[serde(default)]
+    pub cooldown_secs: Option<u32>,
+}
+
+impl ChannelConfig {
+    pub fn is_empty(&self) -> bool {
+        self.llm.is_none()
+            && self.system_prompt.is_none()
+            && self.response_schema.is_none()
+            && self.gen_params == GenerationParams::default()
+            && self.cooldown_secs.is_none()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,25 +521,146 @@ pub struct GuildInfo {
     pub id: String,
     pub our_channels: Vec<String>,
     pub message_log: HashMap<String, Vec<Utterance>>,
-    pub cooldown: u32,
+    /// Rolling summaries of what was said before each channel's log, by channel ID.
+    #[serde(default)]
+    pub summaries: HashMap<String, ChannelSummary>,
+    #[serde(default)]
+    pub capture: CaptureSettings,
+    /// Write a fresh reply when someone edits a message Jeeves has answered.
+    #[serde(default)]
+    pub regenerate_on_edit: bool,
+    /// Post replies as they are written, for models that can stream.
+    #[serde(default)]
+    pub stream_replies: bool,
+    /// When the guild's cooldown after its last reply ends, in seconds since the epoch.
+    #[serde(default)]
+    pub cooldown_until: u64,
+    /// When the cooldowns of channels that have their own end, by channel ID.
+    #[serde(default)]
+    pub channel_cooldowns: HashMap<String, u64>,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub rate_buckets: RateBuckets,
     pub debug: bool,
     pub llm: String,
+    /// Models to try in order when `llm` fails.
+    #[serde(default)]
+    pub fallback_models: Vec<String>,
+    /// Whose embeddings the guild's memories and documents are kept in. Fixed
+    /// when the guild is set up, as they can't be compared with any other's.
+    #[serde(default)]
+    pub embedder: Option<Provider>,
+    /// Tools the model may not call here; all others it may.
+    #[serde(default)]
+    pub disabled_tools: Vec<String>,
+    #[serde(default)]
+    pub gen_params: GenerationParams,
+    /// Tokens spent here, and what they cost.
+    #[serde(default)]
+    pub usage: UsageLedger,
+    /// Caps on spending, and what is spent against them.
+    #[serde(default)]
+    pub quotas: QuotaSettings,
+    /// Which chat template each raw-prompt model uses, by template name.
+    #[serde(default)]
+    pub model_templates: HashMap<String, String>,
+    #[serde(default)]
+    pub custom_templates: HashMap<String, ChatTemplate>,
+    /// How speakers are written into messages whose name the provider can't carry,
+    /// with `{name}` and `{content}`.
+    #[serde(default = "default_attribution")]
+    pub attribution: String,
     pub system_prompt: String,
     pub response_schema: BotResponseSchema,
+    /// What each channel does differently from the rest of the guild, by channel ID.
+    #[serde(default)]
+    pub channel_configs: HashMap<String, ChannelConfig>,
+    /// Channels' own response schemas and generation parameters, from before
+    /// `channel_configs`; folded into it by `migrate_state`.
+    #[serde(default, skip_serializing)]
+    pub channel_response_schemas: HashMap<String, BotResponseSchema>,
+    #[serde(default, skip_serializing)]
+    pub channel_gen_params: HashMap<String, GenerationParams>,
     pub listen_to_roles: Vec<String>,
     pub ignore_roles: Vec<String>,
     pub listen_to_users: Vec<String>,
     pub ignore_users: Vec<String>,
 }
 
+impl GuildInfo {
+    /// The model a channel answers with: its own, or else the guild's.
+    pub fn model_for_channel(&self, channel_id: &String) -> String {
+        self.channel_configs
+            .get(channel_id)
+            .and_then(|c| c.llm.clone())
+            .unwrap_or(self.llm.clone())
+    }
+
+    pub fn system_prompt_for_channel(&self, channel_id: &String) -> &String {
+        self.channel_configs
+            .get(channel_id)
+            .and_then(|c| c.system_prompt.as_ref())
+            .unwrap_or(&self.system_prompt)
+    }
+
+    pub fn cooldown_secs_for_channel(&self, channel_id: &String) -> u32 {
+        self.channel_configs
+            .get(channel_id)
+            .and_then(|c| c.cooldown_secs)
+            .unwrap_or(self.rate_limits.cooldown_secs)
+    }
+
+    /// Change a channel's settings, forgetting them once none are left.
+    pub fn configure_channel(
+        &mut self,
+        channel_id: &String,
+        change: impl FnOnce(&mut ChannelConfig),
+    ) {
+        let config = self.channel_configs.entry(channel_id.clone()).or_default();
+        change(config);
+        if config.is_empty() {
+            self.channel_configs.remove(channel_id);
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JeevesState {
+    /// Which `migrate_state` steps this state has been through.
+    #[serde(default)]
+    pub version: u32,
     pub guilds: HashMap<String, GuildInfo>,
+    /// Our own Discord user, as learned from the gateway's Ready event.
+    #[serde(default)]
+    pub bot_user_id: Option<String>,
+    #[serde(default)]
+    pub bot_username: Option<String>,
+    /// The VFS drive holding each guild's memory index.
+    #[serde(default)]
+    pub memory_drive: Option<String>,
+    /// The VFS drive holding each guild's knowledge base.
+    #[serde(default)]
+    pub knowledge_drive: Option<String>,
+    /// The models guilds may choose from, by name.
+    #[serde(default = "default_models")]
+    pub models: HashMap<String, ModelInfo>,
+    /// What each model cost, from before there was a registry; folded into
+    /// `models` by `migrate_state`.
+    #[serde(default, skip_serializing)]
+    pub model_prices: HashMap<String, ModelPrice>,
 }
 
 pub fn empty_state() -> JeevesState {
     JeevesState {
+        version: STATE_VERSION,
         guilds: HashMap::new(),
+        bot_user_id: None,
+        bot_username: None,
+        memory_drive: None,
+        knowledge_drive: None,
+        models: default_models(),
+        model_prices: HashMap::new(),
     }
 }
 
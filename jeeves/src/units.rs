@@ -0,0 +1,369 @@
+/// A unit, by the names it goes by, as a multiple of its kind's base unit.
+struct Unit {
+    names: &'static [&'static str],
+    kind: &'static str,
+    factor: f64,
+}
+
+const UNITS: &[Unit] = &[
+    // length, in metres
+    Unit {
+        names: &["m", "metre", "metres", "meter", "meters"],
+        kind: "length",
+        factor: 1.0,
+    },
+    Unit {
+        names: &["km", "kilometre", "kilometres", "kilometer", "kilometers"],
+        kind: "length",
+        factor: 1000.0,
+    },
+    Unit {
+        names: &[
+            "cm",
+            "centimetre",
+            "centimetres",
+            "centimeter",
+            "centimeters",
+        ],
+        kind: "length",
+        factor: 0.01,
+    },
+    Unit {
+        names: &[
+            "mm",
+            "millimetre",
+            "millimetres",
+            "millimeter",
+            "millimeters",
+        ],
+        kind: "length",
+        factor: 0.001,
+    },
+    Unit {
+        names: &["in", "inch", "inches", "\""],
+        kind: "length",
+        factor: 0.0254,
+    },
+    Unit {
+        names: &["ft", "foot", "feet", "'"],
+        kind: "length",
+        factor: 0.3048,
+    },
+    Unit {
+        names: &["yd", "yard", "yards"],
+        kind: "length",
+        factor: 0.9144,
+    },
+    Unit {
+        names: &["mi", "mile", "miles"],
+        kind: "length",
+        factor: 1609.344,
+    },
+    Unit {
+        names: &["nmi", "nautical mile", "nautical miles"],
+        kind: "length",
+        factor: 1852.0,
+    },
+    // mass, in kilograms
+    Unit {
+        names: &["kg", "kilogram", "kilograms", "kilo", "kilos"],
+        kind: "mass",
+        factor: 1.0,
+    },
+    Unit {
+        names: &["g", "gram", "grams"],
+        kind: "mass",
+        factor: 0.001,
+    },
+    Unit {
+        names: &["mg", "milligram", "milligrams"],
+        kind: "mass",
+        factor: 0.000001,
+    },
+    Unit {
+        names: &["t", "tonne", "tonnes"],
+        kind: "mass",
+        factor: 1000.0,
+    },
+    Unit {
+        names: &["lb", "lbs", "pound", "pounds"],
+        kind: "mass",
+        factor: 0.45359237,
+    },
+    Unit {
+        names: &["oz", "ounce", "ounces"],
+        kind: "mass",
+        factor: 0.028349523125,
+    },
+    Unit {
+        names: &["st", "stone", "stones"],
+        kind: "mass",
+        factor: 6.35029318,
+    },
+    // volume, in litres
+    Unit {
+        names: &["l", "litre", "litres", "liter", "liters"],
+        kind: "volume",
+        factor: 1.0,
+    },
+    Unit {
+        names: &[
+            "ml",
+            "millilitre",
+            "millilitres",
+            "milliliter",
+            "milliliters",
+        ],
+        kind: "volume",
+        factor: 0.001,
+    },
+    Unit {
+        names: &["gal", "gallon", "gallons", "us gallon", "us gallons"],
+        kind: "volume",
+        factor: 3.785411784,
+    },
+    Unit {
+        names: &[
+            "imperial gallon",
+            "imperial gallons",
+            "uk gallon",
+            "uk gallons",
+        ],
+        kind: "volume",
+        factor: 4.54609,
+    },
+    Unit {
+        names: &["qt", "quart", "quarts"],
+        kind: "volume",
+        factor: 0.946352946,
+    },
+    Unit {
+        names: &["pt", "pint", "pints", "us pint", "us pints"],
+        kind: "volume",
+        factor: 0.473176473,
+    },
+    Unit {
+        names: &["imperial pint", "imperial pints", "uk pint", "uk pints"],
+        kind: "volume",
+        factor: 0.56826125,
+    },
+    Unit {
+        names: &["cup", "cups"],
+        kind: "volume",
+        factor: 0.2365882365,
+    },
+    Unit {
+        names: &["fl oz", "fluid ounce", "fluid ounces"],
+        kind: "volume",
+        factor: 0.0295735295625,
+    },
+    Unit {
+        names: &["tbsp", "tablespoon", "tablespoons"],
+        kind: "volume",
+        factor: 0.01478676478125,
+    },
+    Unit {
+        names: &["tsp", "teaspoon", "teaspoons"],
+        kind: "volume",
+        factor: 0.00492892159375,
+    },
+    // time, in seconds
+    Unit {
+        names: &["s", "sec", "second", "seconds"],
+        kind: "time",
+        factor: 1.0,
+    },
+    Unit {
+        names: &["min", "minute", "minutes"],
+        kind: "time",
+        factor: 60.0,
+    },
+    Unit {
+        names: &["h", "hr", "hour", "hours"],
+        kind: "time",
+        factor: 3600.0,
+    },
+    Unit {
+        names: &["day", "days"],
+        kind: "time",
+        factor: 86400.0,
+    },
+    Unit {
+        names: &["week", "weeks"],
+        kind: "time",
+        factor: 604800.0,
+    },
+    Unit {
+        names: &["year", "years"],
+        kind: "time",
+        factor: 31557600.0,
+    },
+    // speed, in metres per second
+    Unit {
+        names: &["m/s", "metres per second", "meters per second"],
+        kind: "speed",
+        factor: 1.0,
+    },
+    Unit {
+        names: &[
+            "km/h",
+            "kph",
+            "kmh",
+            "kilometres per hour",
+            "kilometers per hour",
+        ],
+        kind: "speed",
+        factor: 1000.0 / 3600.0,
+    },
+    Unit {
+        names: &["mph", "miles per hour"],
+        kind: "speed",
+        factor: 0.44704,
+    },
+    Unit {
+        names: &["kn", "knot", "knots"],
+        kind: "speed",
+        factor: 1852.0 / 3600.0,
+    },
+    // data, in bytes
+    Unit {
+        names: &["b", "byte", "bytes"],
+        kind: "data",
+        factor: 1.0,
+    },
+    Unit {
+        names: &["kb", "kilobyte", "kilobytes"],
+        kind: "data",
+        factor: 1e3,
+    },
+    Unit {
+        names: &["mb", "megabyte", "megabytes"],
+        kind: "data",
+        factor: 1e6,
+    },
+    Unit {
+        names: &["gb", "gigabyte", "gigabytes"],
+        kind: "data",
+        factor: 1e9,
+    },
+    Unit {
+        names: &["tb", "terabyte", "terabytes"],
+        kind: "data",
+        factor: 1e12,
+    },
+    Unit {
+        names: &["kib", "kibibyte", "kibibytes"],
+        kind: "data",
+        factor: 1024.0,
+    },
+    Unit {
+        names: &["mib", "mebibyte", "mebibytes"],
+        kind: "data",
+        factor: 1048576.0,
+    },
+    Unit {
+        names: &["gib", "gibibyte", "gibibytes"],
+        kind: "data",
+        factor: 1073741824.0,
+    },
+];
+
+/// Temperatures don't share a zero, so they convert through Kelvin rather than by factor.
+fn to_kelvin(unit: &str, value: f64) -> Option<f64> {
+    match unit {
+        "c" | "°c" | "celsius" => Some(value + 273.15),
+        "f" | "°f" | "fahrenheit" => Some((value - 32.0) * 5.0 / 9.0 + 273.15),
+        "k" | "kelvin" => Some(value),
+        _ => None,
+    }
+}
+
+fn from_kelvin(unit: &str, kelvin: f64) -> Option<f64> {
+    match unit {
+        "c" | "°c" | "celsius" => Some(kelvin - 273.15),
+        "f" | "°f" | "fahrenheit" => Some((kelvin - 273.15) * 9.0 / 5.0 + 32.0),
+        "k" | "kelvin" => Some(kelvin),
+        _ => None,
+    }
+}
+
+fn find_unit(name: &str) -> Option<&'static Unit> {
+    UNITS.iter().find(|u| u.names.contains(&name))
+}
+
+/// Round away float noise: 12 significant figures is plenty for conversions.
+fn tidy(x: f64) -> String {
+    if x == 0.0 || !x.is_finite() {
+        return x.to_string();
+    }
+    let digits = 12 - (x.abs().log10().floor() as i32 + 1);
+    let scale = 10f64.powi(digits.clamp(-300, 300));
+    ((x * scale).round() / scale).to_string()
+}
+
+pub fn convert_units(value: f64, from: &str, to: &str) -> anyhow::Result<String> {
+    let from_name = from.trim().to_lowercase();
+    let to_name = to.trim().to_lowercase();
+
+    if let Some(kelvin) = to_kelvin(&from_name, value) {
+        let Some(converted) = from_kelvin(&to_name, kelvin) else {
+            return Err(anyhow::anyhow!("I can't convert a temperature to {}", to));
+        };
+        return Ok(format!("{} {} = {} {}", value, from, tidy(converted), to));
+    }
+
+    let Some(from_unit) = find_unit(&from_name) else {
+        return Err(anyhow::anyhow!("I don't know the unit {}", from));
+    };
+    let Some(to_unit) = find_unit(&to_name) else {
+        return Err(anyhow::anyhow!("I don't know the unit {}", to));
+    };
+    if from_unit.kind != to_unit.kind {
+        return Err(anyhow::anyhow!(
+            "{} is a unit of {} but {} is a unit of {}",
+            from,
+            from_unit.kind,
+            to,
+            to_unit.kind
+        ));
+    }
+    let converted = value * from_unit.factor / to_unit.factor;
+    Ok(format!("{} {} = {} {}", value, from, tidy(converted), to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_by_factor() {
+        assert_eq!(
+            convert_units(1.0, "mi", "km").unwrap(),
+            "1 mi = 1.609344 km"
+        );
+        assert_eq!(
+            convert_units(3.0, "Feet", "m").unwrap(),
+            "3 Feet = 0.9144 m"
+        );
+        assert_eq!(
+            convert_units(1.0, "kg", "lb").unwrap(),
+            "1 kg = 2.20462262185 lb"
+        );
+    }
+
+    #[test]
+    fn converts_temperatures_through_kelvin() {
+        assert_eq!(convert_units(100.0, "C", "F").unwrap(), "100 C = 212 F");
+        assert_eq!(
+            convert_units(0.0, "kelvin", "celsius").unwrap(),
+            "0 kelvin = -273.15 celsius"
+        );
+        assert!(convert_units(20.0, "C", "m").is_err());
+    }
+
+    #[test]
+    fn refuses_unknown_or_mismatched_units() {
+        assert!(convert_units(1.0, "furlong", "m").is_err());
+        assert!(convert_units(1.0, "m", "kg").is_err());
+    }
+}
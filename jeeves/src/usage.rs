@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use crate::consts::*;
+use crate::context::*;
+use crate::empty_state;
+use crate::models::*;
+use crate::providers::*;
+use crate::quotas::*;
+use crate::types::*;
+use kinode_process_lib::{get_typed_state, println, set_state};
+
+impl UsageTotals {
+    fn add(&mut self, usage: &TokenUsage, cost: f64) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.cost += cost;
+    }
+}
+
+/// What `usage` of `model` costs, in dollars. Unregistered models are free.
+pub fn usage_cost(models: &HashMap<String, ModelInfo>, model: &str, usage: &TokenUsage) -> f64 {
+    let price = lookup_model(models, model).price;
+    (usage.prompt_tokens as f64 * price.prompt_per_million
+        + usage.completion_tokens as f64 * price.completion_per_million)
+        / 1_000_000.0
+}
+
+/// Charge the tokens a chat completion spent to its guild, channel, user and model.
+pub fn record_usage(
+    guild_id: &String,
+    channel_id: &String,
+    user_id: Option<&String>,
+    model: &str,
+    body: &[u8],
+) {
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(usage) = parse_chat_usage(&provider_for_model(&state.models, model), body) else {
+        return;
+    };
+    let cost = usage_cost(&state.models, model, &usage);
+    let Some(guild) = state.guilds.get_mut(guild_id) else {
+        return;
+    };
+    charge_usage(guild, Some(channel_id), user_id, model, &usage, cost);
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+}
+
+/// What embedding `texts` costs. The llm package doesn't pass on the provider's
+/// count, so the tokens are our estimate.
+pub fn embedding_usage(provider: &Provider, texts: &[&str]) -> (TokenUsage, f64) {
+    let usage = TokenUsage {
+        prompt_tokens: texts.iter().map(|t| estimate_tokens(t) as u64).sum(),
+        completion_tokens: 0,
+    };
+    let price = match provider {
+        Provider::OpenAi => OPENAI_EMBEDDING_PRICE_PER_MILLION,
+        Provider::Lccp => 0.0,
+    };
+    let cost = usage.prompt_tokens as f64 * price / 1_000_000.0;
+    (usage, cost)
+}
+
+/// Charge embedding `texts` to the guild, and to the channel and user they were
+/// for, if any.
+pub fn record_embedding_usage(
+    guild_id: &String,
+    channel_id: Option<&String>,
+    user_id: Option<&String>,
+    provider: &Provider,
+    texts: &[&str],
+) {
+    let (usage, cost) = embedding_usage(provider, texts);
+    let mut state = get_typed_state(|bytes| Ok(serde_json::from_slice::<JeevesState>(&bytes)?))
+        .unwrap_or(empty_state());
+    let Some(guild) = state.guilds.get_mut(guild_id) else {
+        return;
+    };
+    charge_usage(
+        guild,
+        channel_id,
+        user_id,
+        &embedder_name(provider),
+        &usage,
+        cost,
+    );
+    set_state(&serde_json::to_vec(&state).unwrap_or(vec![]));
+}
+
+/// Add `usage` to the guild's ledger and count it against its caps.
+fn charge_usage(
+    guild: &mut GuildInfo,
+    channel_id: Option<&String>,
+    user_id: Option<&String>,
+    model: &str,
+    usage: &TokenUsage,
+    cost: f64,
+) {
+    let ledger = &mut guild.usage;
+    ledger.total.add(usage, cost);
+    if let Some(channel_id) = channel_id {
+        ledger
+            .by_channel
+            .entry(channel_id.clone())
+            .or_default()
+            .add(usage, cost);
+    }
+    if let Some(user_id) = user_id {
+        ledger
+            .by_user
+            .entry(user_id.clone())
+            .or_default()
+            .add(usage, cost);
+    }
+    ledger
+        .by_model
+        .entry(model.to_string())
+        .or_default()
+        .add(usage, cost);
+    charge_quotas(&mut guild.quotas, user_id, usage, cost);
+    println!(
+        "jeeves: {} spent {} prompt and {} completion tokens (${:.4}) in guild {}",
+        model, usage.prompt_tokens, usage.completion_tokens, cost, guild.id
+    );
+}
+
+fn describe_totals(totals: &UsageTotals) -> String {
+    format!(
+        "{} requests, {} prompt + {} completion tokens, ${:.2}",
+        totals.requests, totals.prompt_tokens, totals.completion_tokens, totals.cost
+    )
+}
+
+/// The biggest spenders first.
+fn describe_breakdown(
+    totals: &HashMap<String, UsageTotals>,
+    label: impl Fn(&String) -> String,
+) -> String {
+    if totals.is_empty() {
+        return "(none)".to_string();
+    }
+    let mut entries = totals.iter().collect::<Vec<(&String, &UsageTotals)>>();
+    entries.sort_by(|a, b| {
+        b.1.cost
+            .total_cmp(&a.1.cost)
+            .then(b.1.completion_tokens.cmp(&a.1.completion_tokens))
+    });
+    entries
+        .iter()
+        .take(10)
+        .map(|(key, totals)| format!("{}: {}", label(key), describe_totals(totals)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn describe_usage(ledger: &UsageLedger) -> String {
+    format!(
+        "**Total**: {}\n**By model**:\n{}\n**By channel**:\n{}\n**By user**:\n{}",
+        describe_totals(&ledger.total),
+        describe_breakdown(&ledger.by_model, |m| format!("`{}`", m)),
+        describe_breakdown(&ledger.by_channel, |c| format!("<#{}>", c)),
+        describe_breakdown(&ledger.by_user, |u| format!("<@{}>", u)),
+    )
+}
+
+pub fn describe_prices(models: &HashMap<String, ModelInfo>) -> String {
+    let mut names = models.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    names
+        .iter()
+        .map(|m| {
+            format!(
+                "`{}`: ${} prompt, ${} completion per million tokens",
+                m, models[m].price.prompt_per_million, models[m].price.completion_per_million
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn check_price(price: &ModelPrice) -> anyhow::Result<()> {
+    let (prompt, completion) = (price.prompt_per_million, price.completion_per_million);
+    if prompt < 0.0 || completion < 0.0 || !prompt.is_finite() || !completion.is_finite() {
+        return Err(anyhow::anyhow!("prices can't be negative"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prices_chat_by_the_registry() {
+        let usage = TokenUsage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+        };
+        let cost = usage_cost(&default_models(), "gpt-4", &usage);
+        assert!((cost - 60.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn prices_embeddings_by_provider() {
+        let text = "x".repeat(3_000_000);
+        let (usage, cost) = embedding_usage(&Provider::OpenAi, &[&text]);
+        assert_eq!(usage.prompt_tokens, 1_000_000);
+        assert!((cost - OPENAI_EMBEDDING_PRICE_PER_MILLION).abs() < 1e-9);
+        let (_, cost) = embedding_usage(&Provider::Lccp, &[&text]);
+        assert_eq!(cost, 0.0);
+    }
+}
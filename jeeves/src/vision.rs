@@ -0,0 +1,174 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use base64::Engine;
+
+use crate::consts::*;
+use crate::discord::*;
+use crate::types::*;
+use kinode_process_lib::println;
+
+/// An image downloaded from Discord, ready to send to a model.
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    pub content_type: String,
+    /// Base64.
+    pub data: String,
+}
+
+/// The images of one message of the prompt, by its index there.
+#[derive(Debug, Clone)]
+pub struct MessageImages {
+    pub index: usize,
+    pub images: Vec<EncodedImage>,
+}
+
+/// The image attachments of a Discord message.
+pub fn image_attachments(attachments: &[discord_api::Attachment]) -> Vec<ImageAttachment> {
+    attachments
+        .iter()
+        .filter(|a| {
+            a.content_type
+                .as_ref()
+                .map(|t| t.starts_with("image/"))
+                .unwrap_or(false)
+        })
+        .filter(|a| a.size <= VISION_MAX_IMAGE_BYTES)
+        .take(VISION_MAX_IMAGES)
+        .map(|a| ImageAttachment {
+            url: a.url.clone(),
+            filename: a.filename.clone(),
+            content_type: a.content_type.clone().unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// How an utterance's images appear in the text of a prompt, so that models that
+/// can't see them at least know they were there.
+pub fn describe_images(images: &[ImageAttachment]) -> String {
+    images
+        .iter()
+        .map(|i| format!(" [image: {}]", i.filename))
+        .collect::<String>()
+}
+
+/// The images of the last few messages answered, by message ID, so that retries,
+/// fallbacks and tool rounds don't download them again. Oldest first.
+fn fetched() -> MutexGuard<'static, VecDeque<(String, Vec<EncodedImage>)>> {
+    static FETCHED: OnceLock<Mutex<VecDeque<(String, Vec<EncodedImage>)>>> = OnceLock::new();
+    FETCHED
+        .get_or_init(|| Mutex::new(VecDeque::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// The images of message `message_id`, if they have been downloaded.
+pub fn cached_images(message_id: &String) -> Option<Vec<EncodedImage>> {
+    fetched()
+        .iter()
+        .find(|(id, _)| id == message_id)
+        .map(|(_, encoded)| encoded.clone())
+}
+
+fn cache_images(message_id: &String, encoded: Vec<EncodedImage>) {
+    let mut fetched = fetched();
+    fetched.push_back((message_id.clone(), encoded));
+    while fetched.len() > VISION_CACHED_MESSAGES {
+        fetched.pop_front();
+    }
+}
+
+/// A message's images on their way from Discord, and the replies waiting on them.
+struct ImageFetch {
+    images: Vec<ImageAttachment>,
+    encoded: Vec<Option<EncodedImage>>,
+    outstanding: usize,
+    waiting: Vec<PendingReply>,
+}
+
+fn fetching() -> MutexGuard<'static, HashMap<String, ImageFetch>> {
+    static FETCHING: OnceLock<Mutex<HashMap<String, ImageFetch>>> = OnceLock::new();
+    FETCHING
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Let go of a message's images once its reply is done with them.
+pub fn forget_images(message_id: &String) {
+    fetched().retain(|(id, _)| id != message_id);
+}
+
+/// Start downloading the images of the message `pending` answers, parking it
+/// until they are in. Returns false if none could be asked for, in which case
+/// the message is taken to have no images and the reply can go ahead.
+pub fn fetch_images(pending: &PendingReply, images: &[ImageAttachment]) -> bool {
+    let mut fetching = fetching();
+    if let Some(fetch) = fetching.get_mut(&pending.message_id) {
+        fetch.waiting.push(pending.clone());
+        return true;
+    }
+    let mut outstanding = 0;
+    for (index, image) in images.iter().enumerate() {
+        let context = ResponseContext::ImageFetched {
+            message_id: pending.message_id.clone(),
+            index,
+        };
+        match request_download(&image.url, &context) {
+            Ok(()) => outstanding += 1,
+            Err(e) => println!("jeeves: could not fetch image {}: {}", image.filename, e),
+        }
+    }
+    if outstanding == 0 {
+        drop(fetching);
+        cache_images(&pending.message_id, vec![]);
+        return false;
+    }
+    fetching.insert(
+        pending.message_id.clone(),
+        ImageFetch {
+            images: images.to_vec(),
+            encoded: vec![None; images.len()],
+            outstanding,
+            waiting: vec![pending.clone()],
+        },
+    );
+    true
+}
+
+/// One of a message's images has been downloaded, or `None` if it never will
+/// be. Once the last is in, returns the replies that were waiting on them; any
+/// images that couldn't be fetched are left out.
+pub fn handle_image_fetched(
+    message_id: &String,
+    index: usize,
+    body: Option<&[u8]>,
+) -> Vec<PendingReply> {
+    let mut fetching = fetching();
+    let Some(fetch) = fetching.get_mut(message_id) else {
+        return vec![];
+    };
+    let Some(image) = fetch.images.get(index) else {
+        return vec![];
+    };
+    match body
+        .ok_or(anyhow::anyhow!("the download timed out"))
+        .and_then(downloaded_bytes)
+    {
+        Ok(bytes) => {
+            fetch.encoded[index] = Some(EncodedImage {
+                content_type: image.content_type.clone(),
+                data: base64::engine::general_purpose::STANDARD.encode(bytes),
+            })
+        }
+        Err(e) => println!("jeeves: could not fetch image {}: {}", image.filename, e),
+    }
+    fetch.outstanding = fetch.outstanding.saturating_sub(1);
+    if fetch.outstanding > 0 {
+        return vec![];
+    }
+    let fetch = fetching.remove(message_id).unwrap();
+    drop(fetching);
+    cache_images(message_id, fetch.encoded.into_iter().flatten().collect());
+    fetch.waiting
+}
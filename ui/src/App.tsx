@@ -46,10 +46,132 @@ function App() {
     }
   }, []);
 
+  const [guildId, setGuildId] = useState("");
+  const [file, setFile] = useState<File | undefined>();
+  const [uploadStatus, setUploadStatus] = useState("");
+
+  const uploadDocument = useCallback(async () => {
+    if (!guildId || !file) return;
+    setUploadStatus("Uploading...");
+    try {
+      const text = await file.text();
+      const response = await fetch(`${BASE_URL}/knowledge`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ guild_id: guildId, name: file.name, text }),
+      });
+      setUploadStatus(response.ok ? `Added ${file.name}` : `Upload failed (${response.status})`);
+    } catch (error) {
+      console.error("Error uploading document", error);
+      setUploadStatus("Upload failed");
+    }
+  }, [guildId, file]);
+
+  const [model, setModel] = useState("");
+  const [provider, setProvider] = useState("OpenAi");
+  const [contextLength, setContextLength] = useState("4096");
+  const [vision, setVision] = useState(false);
+  const [tools, setTools] = useState(true);
+  const [promptPrice, setPromptPrice] = useState("0");
+  const [completionPrice, setCompletionPrice] = useState("0");
+  const [modelStatus, setModelStatus] = useState("");
+
+  // The model registry is shared by every guild, so it is edited here rather than from Discord
+  const editModels = useCallback(async (edit: object) => {
+    try {
+      const response = await fetch(`${BASE_URL}/models`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(edit),
+      });
+      setModelStatus(response.ok ? "Saved" : `Failed: ${await response.text()}`);
+    } catch (error) {
+      console.error("Error editing models", error);
+      setModelStatus("Failed");
+    }
+  }, []);
+
+  const saveModel = useCallback(() => editModels({
+    Add: {
+      model,
+      info: {
+        provider,
+        context_length: Number(contextLength),
+        vision,
+        tools,
+        price: {
+          prompt_per_million: Number(promptPrice),
+          completion_per_million: Number(completionPrice),
+        },
+      },
+    },
+  }), [editModels, model, provider, contextLength, vision, tools, promptPrice, completionPrice]);
+
+  const removeModel = useCallback(() => editModels({ Remove: { model } }), [editModels, model]);
+
   return (
     <div className='w-screen h-screen flex flex-col place-items-center place-content-center'>
       <h1>Jeeves</h1>
       <div className="mt-2">UI coming soon™️</div>
+      <div className="mt-4 flex flex-col gap-2">
+        <h2>Add a document to a guild's knowledge</h2>
+        <input
+          placeholder="Guild ID"
+          value={guildId}
+          onChange={(e) => setGuildId(e.target.value)}
+        />
+        <input
+          type="file"
+          accept=".txt,.md,text/*"
+          onChange={(e) => setFile(e.target.files?.[0])}
+        />
+        <button onClick={uploadDocument} disabled={!guildId || !file}>
+          Upload
+        </button>
+        {uploadStatus && <div>{uploadStatus}</div>}
+      </div>
+      <div className="mt-4 flex flex-col gap-2">
+        <h2>Add, change or remove a model</h2>
+        <input
+          placeholder="Model"
+          value={model}
+          onChange={(e) => setModel(e.target.value)}
+        />
+        <select value={provider} onChange={(e) => setProvider(e.target.value)}>
+          <option value="OpenAi">OpenAI</option>
+          <option value="Lccp">llama.cpp</option>
+        </select>
+        <input
+          placeholder="Context length"
+          value={contextLength}
+          onChange={(e) => setContextLength(e.target.value)}
+        />
+        <label>
+          <input type="checkbox" checked={vision} onChange={(e) => setVision(e.target.checked)} />
+          Vision
+        </label>
+        <label>
+          <input type="checkbox" checked={tools} onChange={(e) => setTools(e.target.checked)} />
+          Tools
+        </label>
+        <input
+          placeholder="Prompt $ per million tokens"
+          value={promptPrice}
+          onChange={(e) => setPromptPrice(e.target.value)}
+        />
+        <input
+          placeholder="Completion $ per million tokens"
+          value={completionPrice}
+          onChange={(e) => setCompletionPrice(e.target.value)}
+        />
+        <button onClick={saveModel} disabled={!model}>
+          Save
+        </button>
+        <button onClick={removeModel} disabled={!model}>
+          Remove
+        </button>
+        {modelStatus && <div>{modelStatus}</div>}
+      </div>
     </div>
   );
 }